@@ -1,16 +1,19 @@
 use log::{info, warn};
-use net::{EventQueue, Server};
+use net::{EventQueue, Identifier, Server};
 use message::{deserialize, serialize, PlayerState, ServerMessage};
 use std::{collections::HashMap, time::{Duration, Instant}};
 
 mod message;
 
+/// Every player is in the same room
+const ROOM: &str = "game";
+
 fn main() {
     env_logger::init();
 
     info!("Starting...");
 
-    let (server , queue) = Server::new("127.0.0.1:3000");
+    let (server , queue) = Server::new("127.0.0.1:3000").expect("Server should have started");
 
     event_loop(server, queue);
 }
@@ -20,7 +23,7 @@ fn event_loop(mut server: Server, mut queue: EventQueue) {
     let target_frame_time = Duration::from_nanos(1_000_000_000 / 60);
 
     // Track state
-    let mut players = HashMap::<u32, PlayerState>::new();
+    let mut players = HashMap::<Identifier, PlayerState>::new();
 
     loop {
         // Save current time
@@ -29,29 +32,32 @@ fn event_loop(mut server: Server, mut queue: EventQueue) {
         // Handle server events
         for event in queue.pop_all() {
             match event {
-                net::Event::Open(id) => { 
+                net::Event::Open(id, info) => { 
+                    info!("Player {} joined from {}", id, info.remote_addr);
                     // Inform existing players that we have a new player
-                    players
-                        .keys()
-                        .for_each(|k| server.send_reliable(*k, serialize(ServerMessage::PlayerJoined(id))));
+                    server.join_room(id, ROOM).unwrap_or_else(|err| warn!("{}", err));
+                    server.broadcast_except(ROOM, id, serialize(ServerMessage::PlayerJoined(id))).unwrap_or_else(|err| warn!("{}", err));
                     // Inform new player of existing players
                     players
                         .keys()
-                        .for_each(|k| server.send_reliable(id, serialize(ServerMessage::PlayerJoined(*k))));
+                        .for_each(|k| server.send_reliable(id, serialize(ServerMessage::PlayerJoined(*k))).unwrap_or_else(|err| warn!("{}", err)));
                     // Track new player
                     players.insert(id, PlayerState::default()); 
                 },
-                net::Event::Closed(id) => {
-                    // Untrack player
+                net::Event::ModeChanged(id, mode) => info!("Player {} is now using {:?}", id, mode),
+                net::Event::Backpressure(id, backpressure) => warn!("Player {} {:?}", id, backpressure),
+                net::Event::Closed(id, reason) => {
+                    info!("Player {} left: {}", id, reason);
+                    // Untrack player, who has already left the room
                     players.remove(&id); 
                     // Inform remaining players that player has left
-                    players
-                        .keys()
-                        .for_each(|k| server.send_reliable(*k, serialize(ServerMessage::PlayerLeft(id))));
+                    server.broadcast_room_reliable(ROOM, serialize(ServerMessage::PlayerLeft(id))).unwrap_or_else(|err| warn!("{}", err));
                 },
-                net::Event::Received(id, bytes) => {
+                net::Event::ConnectFailed(id, failure) => warn!("Player {} couldn't connect: {}", id, failure),
+                net::Event::StateChanged(..) => {}, // Not enabled
+                net::Event::Received(id, message) => {
                     // Handle an incoming message from a player
-                    let message = deserialize(bytes);
+                    let message = deserialize(&message.payload);
 
                     #[allow(irrefutable_let_patterns)]
                     if let message::ClientMessage::Update(state) = message {
@@ -62,9 +68,9 @@ fn event_loop(mut server: Server, mut queue: EventQueue) {
         }
 
         // Broadcast locations, which can be unreliable
-        players
-            .keys()
-            .for_each(|k| server.send_unreliable(*k, serialize(ServerMessage::Update(players.clone()))));
+        if !players.is_empty() {
+            server.broadcast_room_unreliable(ROOM, serialize(ServerMessage::Update(players.clone()))).unwrap_or_else(|err| warn!("{}", err));
+        }
         
         // Calculate elapsed time
         let elapsed = frame_start.elapsed();
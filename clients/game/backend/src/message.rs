@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use net::Identifier;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
@@ -22,12 +23,14 @@ impl Default for PlayerState {
     }
 }
 
+// Identifiers are exported as u32 so TypeScript sees a number rather than a bigint, though the JSON carries the full u64
+// JavaScript numbers only hold it exactly until 2^53 connections, which is plenty
 #[derive(TS, Serialize)]
 #[ts(export)]
 pub enum ServerMessage {
-    Update(HashMap<u32, PlayerState>),
-    PlayerJoined(u32),
-    PlayerLeft(u32)
+    Update(#[ts(as = "HashMap<u32, PlayerState>")] HashMap<Identifier, PlayerState>),
+    PlayerJoined(#[ts(as = "u32")] Identifier),
+    PlayerLeft(#[ts(as = "u32")] Identifier)
 }
 
 #[derive(TS, Deserialize)]
@@ -40,6 +43,6 @@ pub fn serialize(message: ServerMessage) -> Vec<u8> {
     serde_json::to_vec(&message).unwrap()
 }
 
-pub fn deserialize(message: Vec<u8>) -> ClientMessage {
-    serde_json::from_str(&String::from_utf8(message).unwrap()).unwrap()
+pub fn deserialize(message: &[u8]) -> ClientMessage {
+    serde_json::from_slice(message).unwrap()
 }
\ No newline at end of file
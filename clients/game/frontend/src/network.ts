@@ -46,6 +46,11 @@ export function setupNetwork() {
             console.log('Data channel opened');
         };
         dataChannel.onmessage = (event) => {
+            // Text messages are pings, echoed so the server can measure the round-trip time
+            if (typeof event.data === 'string') {
+                dataChannel.send(event.data);
+                return;
+            }
             let message = deserializeServerMessage(event);
             if(message) {
                 handleServerMessage(message);
@@ -0,0 +1,53 @@
+use std::{any::Any, fmt::Debug, sync::Arc};
+
+use crate::info::ConnectionInfo;
+
+/// Data attached to a connection when it is accepted, available from `ConnectionInfo::user_data`.
+pub type UserData = Arc<dyn Any + Send + Sync>;
+
+/// Outcome of authenticating a websocket upgrade request.
+#[derive(Debug)]
+pub enum AuthDecision {
+    /// Continue with the handshake, attaching the user data (if any) to the connection.
+    Accept(Option<UserData>),
+    /// Respond with this HTTP status code and body, then close the TCP stream.
+    ///
+    /// The connection only emits an `Event::ConnectFailed` with `ConnectFailure::Rejected`. Status codes which aren't valid errors are replaced with 400 Bad Request.
+    Reject(u16, String),
+}
+
+/// Decides whether to accept a client, before it is given a WebRTC peer connection.
+///
+/// Called from within the websocket handshake with the connection's details, which don't include a candidate pair or user data yet.
+/// As the handshake waits on it, it should not block for long.
+///
+/// Implemented for closures, e.g.
+/// ```no_run
+/// use net::{AuthDecision, ServerBuilder};
+///
+/// let (server, queue) = ServerBuilder::new()
+///     .authenticator(|request: &net::ConnectionInfo| match request.query_param("token") {
+///         Some(token) if token == "secret" => AuthDecision::Accept(None),
+///         _ => AuthDecision::Reject(401, String::from("Bad token")),
+///     })
+///     .build()
+///     .expect("Server should have started");
+/// ```
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate(&self, request: &ConnectionInfo) -> AuthDecision;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&ConnectionInfo) -> AuthDecision + Send + Sync + 'static,
+{
+    fn authenticate(&self, request: &ConnectionInfo) -> AuthDecision {
+        self(request)
+    }
+}
+
+impl Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Authenticator")
+    }
+}
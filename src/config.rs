@@ -0,0 +1,551 @@
+use log::LevelFilter;
+use std::{fmt::Debug, net::IpAddr, sync::Arc, time::Duration};
+
+use crate::{auth::Authenticator, error::ServerError, queue::EventQueue, server::{AsyncServer, Server}};
+
+/// Flavour of the tokio runtime that the server's OS thread will drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeFlavour {
+    /// Everything runs on the server's own OS thread.
+    CurrentThread,
+    /// A work-stealing runtime, using the given number of worker threads (or tokio's default if `None`).
+    MultiThread { worker_threads: Option<usize> },
+}
+
+/// A STUN or TURN server, used by the server to gather reflexive and relayed ICE candidates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceServer {
+    /// URLs of the server, e.g. `stun:stun.l.google.com:19302` or `turn:turn.example.com:3478?transport=udp`.
+    pub urls: Vec<String>,
+    /// Required for TURN servers.
+    pub username: String,
+    /// Required for TURN servers.
+    pub credential: String,
+}
+
+impl IceServer {
+    pub fn stun(url: &str) -> Self {
+        Self { urls: vec![url.to_string()], username: String::new(), credential: String::new() }
+    }
+
+    pub fn turn(url: &str, username: &str, credential: &str) -> Self {
+        Self { urls: vec![url.to_string()], username: username.to_string(), credential: credential.to_string() }
+    }
+}
+
+/// How NAT 1:1 IPs are advertised to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatCandidateType {
+    /// Replace the IP of host candidates, for when the public IP maps straight through to this machine.
+    Host,
+    /// Advertise server reflexive candidates alongside the host candidates.
+    ServerReflexive,
+}
+
+/// What happens to a reliable message sent to a connection whose outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReliableOverflow {
+    /// `Server` handles refuse the message with `ServerError::Congested`. Messages reaching a full queue any other way, e.g. in a broadcast, are dropped.
+    Error,
+    /// Close the connection with `CloseReason::SlowConsumer`, discarding whatever is still queued for it.
+    Disconnect,
+}
+
+/// What happens to an unreliable message sent to a connection whose outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreliableOverflow {
+    /// Drop the message being sent.
+    DropNewest,
+    /// Drop the oldest unreliable message still queued to make room, as it is the most out of date.
+    DropOldest,
+}
+
+/// Which messages sent to a connection before it opens are held until it does, rather than refused with `ServerError::NotYetOpen`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreOpenBuffer {
+    /// Refuse every message.
+    None,
+    /// Hold messages sent over the websocket, e.g. with `send_reliable`.
+    Reliable,
+    /// Hold every message, including those for data channels.
+    All,
+}
+
+/// Decides whether ICE gathers candidates on a network interface, by name.
+#[derive(Clone)]
+pub struct InterfaceFilter(pub(crate) Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl InterfaceFilter {
+    pub fn new(filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(filter))
+    }
+}
+
+impl Debug for InterfaceFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "InterfaceFilter")
+    }
+}
+
+/// Decides whether ICE gathers candidates on an IP address.
+#[derive(Clone)]
+pub struct IpFilter(pub(crate) Arc<dyn Fn(IpAddr) -> bool + Send + Sync>);
+
+impl IpFilter {
+    pub fn new(filter: impl Fn(IpAddr) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(filter))
+    }
+}
+
+impl Debug for IpFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IpFilter")
+    }
+}
+
+/// How hard the data channel tries to deliver each message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reliability {
+    /// Retransmit until delivered.
+    Reliable,
+    /// Give up on a message after retransmitting it this many times, zero never retransmits.
+    MaxRetransmits(u16),
+    /// Give up on a message once it has been unacknowledged for this long, which is clamped to 65535ms.
+    MaxPacketLifeTime(Duration),
+}
+
+/// Options for one of the WebRTC data channels opened with each client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataChannelConfig {
+    /// Name of the data channel, which the client sees and `Server::send` uses to pick it. Must be unique.
+    pub label: String,
+    /// Whether messages are delivered in the order they were sent, which holds up later messages behind lost ones.
+    pub ordered: bool,
+    pub reliability: Reliability,
+}
+
+impl DataChannelConfig {
+    /// Unordered with no retransmits, so that a lost message never delays the others.
+    pub fn unreliable(label: &str) -> Self {
+        Self { label: label.to_string(), ordered: false, reliability: Reliability::MaxRetransmits(0) }
+    }
+
+    /// Unordered but retransmitted until delivered, e.g. for one-off events.
+    pub fn reliable_unordered(label: &str) -> Self {
+        Self { label: label.to_string(), ordered: false, reliability: Reliability::Reliable }
+    }
+}
+
+impl Default for DataChannelConfig {
+    /// An unreliable channel labelled "game".
+    fn default() -> Self {
+        Self::unreliable("game")
+    }
+}
+
+/// Configuration for a `Server`, usually created with a `ServerBuilder`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address the websocket (TCP) listener binds to.
+    pub tcp_addr: String,
+    /// Address the WebRTC (UDP) socket binds to.
+    pub udp_addr: String,
+    /// Capacity of the channel used by `Server` handles to send commands to the server.
+    pub command_capacity: usize,
+    /// Capacity of the channel used by connections to emit events to the server, before they reach the `EventQueue`.
+    pub event_capacity: usize,
+    /// Capacity of each of the channels internal to a single connection.
+    pub connection_capacity: usize,
+    /// Most messages queued to be written to each of a connection's transports, i.e. its websocket and every data channel.
+    ///
+    /// Unreliable messages falling back to the websocket may only use half of its queue, leaving room for reliable ones.
+    pub outbound_limit: usize,
+    /// What happens to reliable messages sent to a full queue, over the websocket or a reliable data channel.
+    pub reliable_overflow: ReliableOverflow,
+    /// What happens to unreliable messages sent to a full queue.
+    pub unreliable_overflow: UnreliableOverflow,
+    /// Messages sent to connections before they open which are held until they do, up to `outbound_limit` per connection.
+    ///
+    /// They are sent in order once `Event::Open` has been pushed, and broadcasts or `send_many` include connections which haven't opened yet.
+    /// Reliable messages beyond the limit are treated as for a full queue, following `reliable_overflow`.
+    pub pre_open_buffer: PreOpenBuffer,
+    /// Messages queued for any one transport at which a connection is reported as falling behind, see `Backpressure`.
+    pub slow_consumer_depth: usize,
+    /// Bytes buffered by any one data channel at which a connection is reported as falling behind.
+    pub slow_consumer_buffered: usize,
+    /// How often to ping each client over the websocket, or `None` to disable pings. Pongs are used to measure `Rtt::websocket`.
+    pub ping_interval: Option<Duration>,
+    /// How long a client can go without sending anything (including pongs) before it is closed with `CloseReason::Timeout`.
+    /// 
+    /// Only enforced when `ping_interval` is set.
+    pub pong_timeout: Duration,
+    /// If set, how often to ping each client on its first data channel, to measure `Rtt::data_channel`.
+    ///
+    /// Pings are text messages like `{"type":"ping","sent":123}`, which clients should send back unchanged (as text or binary).
+    /// Echoed pings aren't reported as `Event::Received`. Clients which don't echo them just never get a data channel RTT.
+    pub data_channel_ping: Option<Duration>,
+    /// How long ICE can go without connectivity before it is considered disconnected.
+    pub ice_disconnected_timeout: Duration,
+    /// How long ICE can go without connectivity before it is considered failed, closing the connection.
+    pub ice_failed_timeout: Duration,
+    /// How often ICE sends keepalives when there is no other traffic.
+    pub ice_keepalive_interval: Duration,
+    /// STUN and TURN servers used when gathering candidates, none by default.
+    pub ice_servers: Vec<IceServer>,
+    /// Public IPs to advertise in place of (or alongside) the private IPs the server sees, e.g. in Docker or on a cloud VM.
+    pub nat_1to1_ips: Vec<IpAddr>,
+    /// How `nat_1to1_ips` are advertised.
+    pub nat_1to1_candidate_type: NatCandidateType,
+    /// If set, only interfaces it accepts are used to gather candidates.
+    pub interface_filter: Option<InterfaceFilter>,
+    /// If set, only IPs it accepts are used to gather candidates.
+    pub ip_filter: Option<IpFilter>,
+    /// How long a client has to finish the websocket handshake (including TLS), after which it fails with `ConnectFailure::HandshakeTimeout`.
+    pub handshake_timeout: Duration,
+    /// How long the data channels have to open once the websocket handshake has finished, after which the connection fails with `ConnectFailure::WebRtcTimeout`.
+    ///
+    /// Connections which have fallen back to the websocket by then are unaffected, see `webrtc_fallback_timeout`.
+    pub webrtc_timeout: Duration,
+    /// If set, connections whose data channels haven't opened within this time are opened anyway, in `TransportMode::WebSocketOnly`.
+    ///
+    /// They are upgraded to WebRTC if the data channels open later. Connections are also opened this way if ICE fails before the timeout.
+    pub webrtc_fallback_timeout: Option<Duration>,
+    /// Whether to push an `Event::StateChanged` each time a connection moves on to another `ConnectionState`.
+    pub state_events: bool,
+    /// Data channels opened with each client, the first of which is used by `send_unreliable`.
+    ///
+    /// A connection only opens once all of them have. There must be at least one, and labels must be unique.
+    pub data_channels: Vec<DataChannelConfig>,
+    /// Runtime used to drive the server, unused by `AsyncServer` which runs on the caller's runtime.
+    pub runtime: RuntimeFlavour,
+    /// If set, the maximum log level is set to this value when the server is built.
+    ///
+    /// Note that this is global to the `log` crate, so it will also apply to other logging in your application.
+    pub log_level: Option<LevelFilter>,
+    /// If set, the websocket listener serves `wss://` using these certificates.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// If set, decides whether to accept each client during its websocket handshake.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl ServerConfig {
+    /// Checks the options which can't be enforced by their types.
+    pub(crate) fn validate(&self) -> Result<(), ServerError> {
+        if self.data_channels.is_empty() {
+            return Err(ServerError::InvalidConfig(String::from("At least one data channel is required")));
+        }
+
+        // Channels can't be created without room for at least one message
+        let capacities = [("Command", self.command_capacity), ("Event", self.event_capacity), ("Connection", self.connection_capacity)];
+        if let Some((name, _)) = capacities.iter().find(|(_, capacity)| *capacity == 0) {
+            return Err(ServerError::InvalidConfig(format!("{} capacity must be at least 1", name)));
+        }
+
+        if self.outbound_limit == 0 {
+            return Err(ServerError::InvalidConfig(String::from("Outbound limit must be at least 1")));
+        }
+
+        for (i, channel) in self.data_channels.iter().enumerate() {
+            if self.data_channels[..i].iter().any(|other| other.label == channel.label) {
+                return Err(ServerError::InvalidConfig(format!("Data channel label '{}' is used more than once", channel.label)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Position of the data channel with this label.
+    pub(crate) fn data_channel_index(&self, label: &str) -> Option<usize> {
+        self.data_channels.iter().position(|channel| channel.label == label)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp_addr: String::from("127.0.0.1:3000"),
+            udp_addr: String::from("127.0.0.1:3000"),
+            command_capacity: 1024,
+            event_capacity: 1024,
+            connection_capacity: 1024,
+            outbound_limit: 256,
+            reliable_overflow: ReliableOverflow::Disconnect,
+            unreliable_overflow: UnreliableOverflow::DropOldest,
+            pre_open_buffer: PreOpenBuffer::None,
+            slow_consumer_depth: 64,
+            slow_consumer_buffered: 1024 * 1024,
+            ping_interval: Some(Duration::from_secs(5)),
+            pong_timeout: Duration::from_secs(15),
+            data_channel_ping: None,
+            ice_disconnected_timeout: Duration::from_secs(5),
+            ice_failed_timeout: Duration::from_secs(25),
+            ice_keepalive_interval: Duration::from_secs(2),
+            ice_servers: Vec::new(),
+            nat_1to1_ips: Vec::new(),
+            nat_1to1_candidate_type: NatCandidateType::Host,
+            interface_filter: None,
+            ip_filter: None,
+            handshake_timeout: Duration::from_secs(10),
+            webrtc_timeout: Duration::from_secs(30),
+            webrtc_fallback_timeout: None,
+            state_events: false,
+            data_channels: vec![DataChannelConfig::default()],
+            runtime: RuntimeFlavour::CurrentThread,
+            log_level: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            authenticator: None,
+        }
+    }
+}
+
+/// Builder for a `Server`, starting from the defaults of `ServerConfig`.
+///
+/// ```no_run
+/// use net::ServerBuilder;
+///
+/// let (server, queue) = ServerBuilder::new()
+///     .tcp_addr("0.0.0.0:3000")
+///     .udp_addr("0.0.0.0:3001")
+///     .connection_capacity(256)
+///     .build()
+///     .expect("Server should have started");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from an existing configuration.
+    pub fn from_config(config: ServerConfig) -> Self {
+        Self { config }
+    }
+
+    /// Use the same address for both the TCP listener and the UDP socket.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.config.tcp_addr = addr.to_string();
+        self.config.udp_addr = addr.to_string();
+        self
+    }
+
+    pub fn tcp_addr(mut self, addr: &str) -> Self {
+        self.config.tcp_addr = addr.to_string();
+        self
+    }
+
+    pub fn udp_addr(mut self, addr: &str) -> Self {
+        self.config.udp_addr = addr.to_string();
+        self
+    }
+
+    pub fn command_capacity(mut self, capacity: usize) -> Self {
+        self.config.command_capacity = capacity;
+        self
+    }
+
+    pub fn event_capacity(mut self, capacity: usize) -> Self {
+        self.config.event_capacity = capacity;
+        self
+    }
+
+    pub fn connection_capacity(mut self, capacity: usize) -> Self {
+        self.config.connection_capacity = capacity;
+        self
+    }
+
+    /// Limit the messages queued for each of a connection's transports, and what happens to messages sent once a queue is full.
+    pub fn outbound_limit(mut self, limit: usize, reliable: ReliableOverflow, unreliable: UnreliableOverflow) -> Self {
+        self.config.outbound_limit = limit;
+        self.config.reliable_overflow = reliable;
+        self.config.unreliable_overflow = unreliable;
+        self
+    }
+
+    /// Hold messages sent to connections which haven't opened yet, see `ServerConfig::pre_open_buffer`.
+    pub fn buffer_until_open(mut self, buffer: PreOpenBuffer) -> Self {
+        self.config.pre_open_buffer = buffer;
+        self
+    }
+
+    /// Push an `Event::StateChanged` each time a connection moves on to another stage, see `ServerConfig::state_events`.
+    pub fn state_events(mut self) -> Self {
+        self.config.state_events = true;
+        self
+    }
+
+    /// Report a connection as falling behind once a queue holds `depth` messages, or a data channel buffers `buffered` bytes.
+    pub fn slow_consumer(mut self, depth: usize, buffered: usize) -> Self {
+        self.config.slow_consumer_depth = depth;
+        self.config.slow_consumer_buffered = buffered;
+        self
+    }
+
+    /// Ping clients over the websocket at this interval, closing them if nothing is received within `pong_timeout`.
+    pub fn heartbeat(mut self, ping_interval: Duration, pong_timeout: Duration) -> Self {
+        self.config.ping_interval = Some(ping_interval);
+        self.config.pong_timeout = pong_timeout;
+        self
+    }
+
+    /// Don't ping clients over the websocket, so silent clients are only detected by ICE.
+    pub fn disable_heartbeat(mut self) -> Self {
+        self.config.ping_interval = None;
+        self
+    }
+
+    /// Ping clients on their first data channel at this interval, see `ServerConfig::data_channel_ping`.
+    pub fn data_channel_ping(mut self, interval: Duration) -> Self {
+        self.config.data_channel_ping = Some(interval);
+        self
+    }
+
+    /// Timeouts for ICE connectivity, after which a connection is considered disconnected and then failed.
+    pub fn ice_timeouts(mut self, disconnected: Duration, failed: Duration) -> Self {
+        self.config.ice_disconnected_timeout = disconnected;
+        self.config.ice_failed_timeout = failed;
+        self
+    }
+
+    pub fn ice_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.config.ice_keepalive_interval = interval;
+        self
+    }
+
+    /// Add a STUN or TURN server to gather candidates with.
+    pub fn ice_server(mut self, server: IceServer) -> Self {
+        self.config.ice_servers.push(server);
+        self
+    }
+
+    /// Advertise these public IPs to clients, see `NatCandidateType`.
+    pub fn nat_1to1_ips(mut self, ips: impl IntoIterator<Item = IpAddr>, candidate_type: NatCandidateType) -> Self {
+        self.config.nat_1to1_ips = ips.into_iter().collect();
+        self.config.nat_1to1_candidate_type = candidate_type;
+        self
+    }
+
+    /// Only gather candidates on network interfaces whose name the filter accepts.
+    pub fn interface_filter(mut self, filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.config.interface_filter = Some(InterfaceFilter::new(filter));
+        self
+    }
+
+    /// Only gather candidates on IPs the filter accepts.
+    pub fn ip_filter(mut self, filter: impl Fn(IpAddr) -> bool + Send + Sync + 'static) -> Self {
+        self.config.ip_filter = Some(IpFilter::new(filter));
+        self
+    }
+
+    /// Fail connections which haven't finished the websocket handshake, or opened their data channels after it, in time.
+    pub fn connect_timeouts(mut self, handshake: Duration, webrtc: Duration) -> Self {
+        self.config.handshake_timeout = handshake;
+        self.config.webrtc_timeout = webrtc;
+        self
+    }
+
+    /// Open connections over the websocket alone if WebRTC hasn't connected within the timeout, see `ServerConfig::webrtc_fallback_timeout`.
+    pub fn webrtc_fallback(mut self, timeout: Duration) -> Self {
+        self.config.webrtc_fallback_timeout = Some(timeout);
+        self
+    }
+
+    /// Replace the data channels opened with each client, the first of which is used by `send_unreliable`.
+    pub fn data_channels(mut self, data_channels: impl IntoIterator<Item = DataChannelConfig>) -> Self {
+        self.config.data_channels = data_channels.into_iter().collect();
+        self
+    }
+
+    pub fn runtime(mut self, runtime: RuntimeFlavour) -> Self {
+        self.config.runtime = runtime;
+        self
+    }
+
+    pub fn log_level(mut self, level: LevelFilter) -> Self {
+        self.config.log_level = Some(level);
+        self
+    }
+
+    /// Serve `wss://` with these certificates, which can be reloaded whilst the server runs.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: crate::tls::TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Accept or reject clients during their websocket handshake, see `Authenticator`.
+    pub fn authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.config.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Returns the configuration built so far.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Create the server, which will be spawned on a new OS thread.
+    /// 
+    /// Fails if the runtime can't be created or either address can't be bound.
+    pub fn build(self) -> Result<(Server, EventQueue), ServerError> {
+        Server::with_config(self.config)
+    }
+
+    /// Create the server as a task on the current tokio runtime, ignoring the `runtime` option.
+    /// 
+    /// Fails if either address can't be bound.
+    pub async fn build_async(self) -> Result<(AsyncServer, EventQueue), ServerError> {
+        AsyncServer::new(self.config).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataChannelConfig, ReliableOverflow, RuntimeFlavour, ServerBuilder, UnreliableOverflow};
+    use crate::error::ServerError;
+
+    #[test]
+    fn builder_sets_fields() {
+        let builder = ServerBuilder::new()
+            .bind("0.0.0.0:4000")
+            .udp_addr("0.0.0.0:4001")
+            .connection_capacity(8)
+            .outbound_limit(16, ReliableOverflow::Error, UnreliableOverflow::DropNewest)
+            .runtime(RuntimeFlavour::MultiThread { worker_threads: Some(2) });
+
+        let config = builder.config();
+        assert_eq!(config.tcp_addr, "0.0.0.0:4000");
+        assert_eq!(config.udp_addr, "0.0.0.0:4001");
+        assert_eq!(config.connection_capacity, 8);
+        assert_eq!(config.command_capacity, 1024);
+        assert_eq!((config.outbound_limit, config.reliable_overflow, config.unreliable_overflow), (16, ReliableOverflow::Error, UnreliableOverflow::DropNewest));
+        assert_eq!(config.runtime, RuntimeFlavour::MultiThread { worker_threads: Some(2) });
+    }
+
+    #[test]
+    fn data_channels_are_validated() {
+        let channels = [DataChannelConfig::unreliable("state"), DataChannelConfig::reliable_unordered("events")];
+        let config = ServerBuilder::new().data_channels(channels).config().clone();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.data_channel_index("events"), Some(1));
+
+        let config = ServerBuilder::new().data_channels([]).config().clone();
+        assert!(matches!(config.validate(), Err(ServerError::InvalidConfig(_))));
+
+        let channels = [DataChannelConfig::unreliable("state"), DataChannelConfig::reliable_unordered("state")];
+        let config = ServerBuilder::new().data_channels(channels).config().clone();
+        assert!(matches!(config.validate(), Err(ServerError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn capacities_are_validated() {
+        assert!(matches!(ServerBuilder::new().command_capacity(0).config().validate(), Err(ServerError::InvalidConfig(_))));
+        assert!(matches!(ServerBuilder::new().event_capacity(0).config().validate(), Err(ServerError::InvalidConfig(_))));
+        assert!(matches!(ServerBuilder::new().connection_capacity(0).config().validate(), Err(ServerError::InvalidConfig(_))));
+    }
+}
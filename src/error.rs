@@ -0,0 +1,57 @@
+use std::{error::Error, fmt::Display, io};
+
+use crate::event::Identifier;
+
+/// Errors returned by `Server` when starting up or when asked to act on a connection.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The server could not bind to one of its configured addresses.
+    Bind(io::Error),
+    /// The async runtime for the server could not be created.
+    Runtime(io::Error),
+    /// The configuration is invalid, for the given reason.
+    InvalidConfig(String),
+    /// TLS certificates or keys could not be loaded, only returned with the `tls` feature.
+    Tls(String),
+    /// No connection with this identifier exists, it may have already closed.
+    UnknownConnection(Identifier),
+    /// The connection exists, but has not emitted `Event::Open` yet.
+    NotYetOpen(Identifier),
+    /// No data channel with this label has been configured.
+    UnknownChannel(String),
+    /// The server is no longer running, so can't accept any more commands.
+    ServerStopped,
+    /// The server's command queue is full, the command was not sent.
+    QueueFull,
+    /// The websocket close code can't be sent by a server, e.g. 1005 (no status) or 1006 (abnormal closure).
+    InvalidCloseCode(u16),
+    /// The connection's outbound queue is full, see `ReliableOverflow::Error`. The message was not sent.
+    Congested(Identifier),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Bind(err) => write!(f, "Failed to bind: {}", err),
+            ServerError::Runtime(err) => write!(f, "Failed to create runtime: {}", err),
+            ServerError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+            ServerError::Tls(reason) => write!(f, "TLS error: {}", reason),
+            ServerError::UnknownConnection(id) => write!(f, "Unknown connection with id={}", id),
+            ServerError::NotYetOpen(id) => write!(f, "Connection with id={} is not open yet", id),
+            ServerError::UnknownChannel(label) => write!(f, "Unknown data channel '{}'", label),
+            ServerError::ServerStopped => write!(f, "Server has stopped"),
+            ServerError::QueueFull => write!(f, "Server command queue is full"),
+            ServerError::InvalidCloseCode(code) => write!(f, "Close code {} can't be sent", code),
+            ServerError::Congested(id) => write!(f, "Outbound queue of connection {} is full", id),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Bind(err) | ServerError::Runtime(err) => Some(err),
+            _ => None,
+        }
+    }
+}
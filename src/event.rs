@@ -1,8 +1,202 @@
-pub type Identifier = u32;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, sync::Arc, time::Instant};
+
+use crate::info::ConnectionInfo;
+
+/// Identifies a connection, assigned in increasing order and never reused during the lifetime of a server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Identifier(u64);
+
+impl Identifier {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The identifier following this one.
+    pub(crate) fn next(self) -> Self {
+        Identifier(self.0 + 1)
+    }
+}
+
+impl From<u64> for Identifier {
+    fn from(value: u64) -> Self {
+        Identifier(value)
+    }
+}
+
+impl From<Identifier> for u64 {
+    fn from(value: Identifier) -> Self {
+        value.0
+    }
+}
+
+impl Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug)]
 pub enum Event {
-    Open(Identifier),
-    Closed(Identifier), // + reason
-    Received(Identifier, Vec<u8>)
-}
\ No newline at end of file
+    Open(Identifier, ConnectionInfo),
+    /// An open connection has switched transport mode, which only happens when upgrading to WebRTC after falling back.
+    ModeChanged(Identifier, TransportMode),
+    /// An open connection has started or stopped falling behind on the messages sent to it.
+    Backpressure(Identifier, Backpressure),
+    Closed(Identifier, CloseReason),
+    /// A connection failed before it opened, so no other events are pushed for it.
+    ///
+    /// Pushed for every connection which doesn't open, including clients rejected by the `Authenticator` and connections killed or shut down before they opened.
+    ConnectFailed(Identifier, ConnectFailure),
+    /// A connection has moved on to another stage, only pushed if `ServerConfig::state_events` is set.
+    StateChanged(Identifier, ConnectionState),
+    Received(Identifier, Message)
+}
+
+/// The stage a connection has reached, see `Server::state`.
+///
+/// Connections usually move through these in order, though some stages are skipped if the client is quick or WebRTC can't connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The TCP connection was accepted, and the websocket handshake (including TLS if enabled) is in progress.
+    Accepted,
+    /// The websocket handshake has finished.
+    Upgraded,
+    /// The SDP offer has been sent to the client.
+    OfferSent,
+    /// The client's SDP answer has been applied.
+    AnswerApplied,
+    /// ICE is checking candidate pairs.
+    IceChecking,
+    /// ICE and DTLS have connected, and the data channels are opening.
+    Connected,
+    /// `Event::Open` has been pushed, whichever `TransportMode` the connection uses.
+    Open,
+    /// The server has asked the connection to close, and is waiting for it to finish.
+    Closing,
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Accepted => write!(f, "Accepted"),
+            ConnectionState::Upgraded => write!(f, "Websocket upgraded"),
+            ConnectionState::OfferSent => write!(f, "SDP offer sent"),
+            ConnectionState::AnswerApplied => write!(f, "SDP answer applied"),
+            ConnectionState::IceChecking => write!(f, "ICE checking"),
+            ConnectionState::Connected => write!(f, "Data channels opening"),
+            ConnectionState::Open => write!(f, "Open"),
+            ConnectionState::Closing => write!(f, "Closing"),
+        }
+    }
+}
+
+/// Whether a connection is keeping up with the messages sent to it, see `ServerConfig::slow_consumer_depth`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// An outbound queue or data channel buffer has reached its slow consumer threshold.
+    FellBehind,
+    /// Every outbound queue and data channel buffer is back below half of its threshold.
+    CaughtUp,
+}
+
+/// Which transports an open connection is using.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMode {
+    /// The websocket is used for reliable messages, and WebRTC data channels for everything else.
+    WebRtc,
+    /// WebRTC couldn't connect in time, so every message goes over the websocket.
+    ///
+    /// Unreliable messages are dropped if the websocket is congested.
+    WebSocketOnly,
+}
+
+/// A message received from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transport: Transport,
+    /// When the message was read off its transport, before it was queued.
+    pub received_at: Instant,
+    /// Shares the buffer it was read into, so cloning is cheap.
+    pub payload: Bytes,
+}
+
+impl Message {
+    /// A message which has just been received.
+    pub(crate) fn new(transport: Transport, payload: Bytes) -> Self {
+        Self { transport, received_at: Instant::now(), payload }
+    }
+}
+
+/// How a message arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    WebSocket,
+    /// The data channel with this label.
+    DataChannel(Arc<str>),
+}
+
+/// Why a connection was closed, reported alongside Event::Closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client sent a websocket close frame, the code is 1005 (no status) if the frame was empty.
+    ClientClosed { code: u16, reason: String },
+    /// The websocket stream failed, e.g. a protocol error or the TCP connection dropping without a close frame.
+    WebSocketError(String),
+    /// The WebRTC data channel was closed.
+    DataChannelClosed,
+    /// ICE connectivity between the client and server failed.
+    IceFailure,
+    /// Killed by the server, using `Server::kill` or `Server::kill_with`.
+    Killed,
+    /// The client stopped responding.
+    Timeout,
+    /// The server is shutting down.
+    ServerShutdown,
+    /// An outbound queue filled up, see `ReliableOverflow::Disconnect`.
+    SlowConsumer,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::ClientClosed { code, reason } => write!(f, "Client closed with code {}: '{}'", code, reason),
+            CloseReason::WebSocketError(err) => write!(f, "Websocket error: {}", err),
+            CloseReason::DataChannelClosed => write!(f, "Data channel closed"),
+            CloseReason::IceFailure => write!(f, "ICE failure"),
+            CloseReason::Killed => write!(f, "Killed by server"),
+            CloseReason::Timeout => write!(f, "Timed out"),
+            CloseReason::ServerShutdown => write!(f, "Server shutdown"),
+            CloseReason::SlowConsumer => write!(f, "Too slow to receive messages"),
+        }
+    }
+}
+
+/// Why a connection failed before it opened, reported with `Event::ConnectFailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectFailure {
+    /// The websocket handshake, including TLS if enabled, didn't finish within `ServerConfig::handshake_timeout`.
+    HandshakeTimeout,
+    /// The websocket handshake failed, e.g. the request wasn't a valid upgrade.
+    HandshakeFailed(String),
+    /// The `Authenticator` rejected the client, which was sent this HTTP status code.
+    Rejected(u16),
+    /// The data channels didn't open within `ServerConfig::webrtc_timeout`, e.g. the client never answered the SDP offer.
+    WebRtcTimeout,
+    /// The connection closed whilst WebRTC was connecting, e.g. the client went away or ICE failed.
+    Closed(CloseReason),
+}
+
+impl Display for ConnectFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectFailure::HandshakeTimeout => write!(f, "Websocket handshake timed out"),
+            ConnectFailure::HandshakeFailed(err) => write!(f, "Websocket handshake failed: {}", err),
+            ConnectFailure::Rejected(status) => write!(f, "Rejected with status {}", status),
+            ConnectFailure::WebRtcTimeout => write!(f, "WebRTC didn't connect in time"),
+            ConnectFailure::Closed(reason) => write!(f, "Closed whilst connecting: {}", reason),
+        }
+    }
+}
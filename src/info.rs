@@ -0,0 +1,164 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{auth::UserData, event::TransportMode};
+
+/// Details about a connection, captured from its websocket upgrade request and WebRTC connection.
+///
+/// Reported with `Event::Open`, and available from `Server::connection_info` whilst the connection is open.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Address of the client's end of the TCP connection.
+    pub remote_addr: SocketAddr,
+    /// Path of the upgrade request, e.g. `/play`.
+    pub path: String,
+    /// Raw query string of the upgrade request, without the leading `?`.
+    pub query: Option<String>,
+    /// Headers of the upgrade request in the order they were received, with lowercase names.
+    pub headers: Vec<(String, String)>,
+    /// Whether the connection is using WebRTC, or has fallen back to the websocket alone.
+    pub transport_mode: TransportMode,
+    /// ICE candidate pair selected for the data channels, if it could be determined.
+    pub candidate_pair: Option<CandidatePair>,
+    /// Data attached by the `Authenticator` when it accepted the connection.
+    pub user_data: Option<UserData>,
+}
+
+impl ConnectionInfo {
+    /// Value of the first header with this name, which is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// User data attached by the `Authenticator`, if it is of type `T`.
+    pub fn user_data<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.user_data.as_deref()?.downcast_ref()
+    }
+
+    /// Percent-decoded value of the first query parameter with this name, e.g. `room` for `?room=abc`.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(key, _)| decode(key) == name)
+            .map(|(_, value)| decode(value))
+    }
+}
+
+/// Pair of ICE candidates that WebRTC traffic flows between.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidatePair {
+    pub local: Candidate,
+    pub remote: Candidate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub ip: String,
+    pub port: u16,
+    /// One of `host`, `srflx`, `prflx` or `relay`.
+    pub kind: String,
+}
+
+/// Round-trip times measured for a connection, see `Server::rtt`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Rtt {
+    /// From websocket pings, which carry reliable messages. None until a pong has been received.
+    pub websocket: Option<RttEstimate>,
+    /// From pings on the first data channel, only measured if `ServerConfig::data_channel_ping` is set and the client echoes them.
+    pub data_channel: Option<RttEstimate>,
+}
+
+/// Smoothed round-trip time and its variation, estimated as in RFC 6298.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimate {
+    /// Exponentially weighted average of the samples (SRTT).
+    pub smoothed: Duration,
+    /// Mean deviation of the samples from the smoothed value (RTTVAR).
+    pub variance: Duration,
+    /// The most recent sample.
+    pub latest: Duration,
+}
+
+impl RttEstimate {
+    /// Updates the estimate with a new sample, or starts one from it.
+    pub(crate) fn sample(previous: Option<Self>, rtt: Duration) -> Self {
+        match previous {
+            None => Self { smoothed: rtt, variance: rtt / 2, latest: rtt },
+            // RTTVAR is updated first, using the previous SRTT
+            Some(estimate) => Self {
+                variance: estimate.variance * 3 / 4 + estimate.smoothed.abs_diff(rtt) / 4,
+                smoothed: estimate.smoothed * 7 / 8 + rtt / 8,
+                latest: rtt,
+            },
+        }
+    }
+}
+
+/// Decodes `application/x-www-form-urlencoded` text, leaving invalid escapes as they are.
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    },
+                    None => decoded.push(b'%'),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ConnectionInfo, RttEstimate};
+    use crate::event::TransportMode;
+
+    #[test]
+    fn reads_headers_and_query() {
+        let info = ConnectionInfo {
+            remote_addr: "127.0.0.1:5000".parse().unwrap(),
+            path: String::from("/play"),
+            query: Some(String::from("room=ab%20c&name=x+y&empty&bad=%zz")),
+            headers: vec![(String::from("user-agent"), String::from("test"))],
+            transport_mode: TransportMode::WebRtc,
+            candidate_pair: None,
+            user_data: None,
+        };
+
+        assert_eq!(info.header("User-Agent"), Some("test"));
+        assert_eq!(info.header("cookie"), None);
+        assert_eq!(info.query_param("room").as_deref(), Some("ab c"));
+        assert_eq!(info.query_param("name").as_deref(), Some("x y"));
+        assert_eq!(info.query_param("empty").as_deref(), Some(""));
+        assert_eq!(info.query_param("bad").as_deref(), Some("%zz"));
+        assert_eq!(info.query_param("missing"), None);
+    }
+
+    #[test]
+    fn smooths_rtt() {
+        let ms = Duration::from_millis;
+        let estimate = RttEstimate::sample(None, ms(80));
+        assert_eq!((estimate.smoothed, estimate.variance), (ms(80), ms(40)));
+
+        let estimate = RttEstimate::sample(Some(estimate), ms(160));
+        assert_eq!((estimate.smoothed, estimate.variance, estimate.latest), (ms(90), ms(50), ms(160)));
+    }
+}
@@ -1,7 +1,22 @@
+mod auth;
+mod config;
+mod error;
 mod event;
+mod info;
 mod queue;
 mod server;
+#[cfg(feature = "tls")]
+mod tls;
 
+pub use auth::{AuthDecision, Authenticator, UserData};
 pub use queue::EventQueue;
-pub use event::Event;
-pub use server::Server;
+pub use event::{Backpressure, CloseReason, ConnectFailure, ConnectionState, Event, Identifier, Message, Transport, TransportMode};
+pub use error::ServerError;
+pub use info::{Candidate, CandidatePair, ConnectionInfo, Rtt, RttEstimate};
+pub use server::{AsyncServer, Server};
+pub use bytes::Bytes;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+pub use config::{DataChannelConfig, IceServer, InterfaceFilter, IpFilter, NatCandidateType, PreOpenBuffer, Reliability, ReliableOverflow, RuntimeFlavour, ServerBuilder, ServerConfig, UnreliableOverflow};
@@ -1,47 +1,131 @@
-use std::{collections::VecDeque, sync::{Arc, Mutex}};
+use std::{collections::VecDeque, pin::Pin, sync::{atomic::{AtomicBool, Ordering}, Arc, Condvar, Mutex, MutexGuard}, task::{Context, Poll}, time::Duration};
+
+use futures_util::{task::AtomicWaker, Stream};
 
 use crate::event::Event;
 
 /// An event queue, for receiving events that occur on the server.
 /// 
 /// Can be freely cloned, will point to the same underlying message buffer.
+/// 
+/// Events can be taken without waiting (`pop_all`, `pop_up_to`, `pop_into`), by blocking the current thread (`wait`, `wait_timeout`, `iter`),
+/// or asynchronously as a `Stream`, all of which end once the server has stopped and every event has been taken.
+/// Only one task should poll the stream at a time, as only the most recent one will be woken.
 #[derive(Default, Clone)]
 pub struct EventQueue {
     queue: Arc<Mutex< VecDeque<Event> >>,
+    waker: Arc<AtomicWaker>,
+    // Wakes threads blocked in wait() or wait_timeout()
+    ready: Arc<Condvar>,
+    closed: Arc<AtomicBool>,
 }
 
 impl EventQueue {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Event>> {
+        self.queue.lock().expect("Lock should not be poisoned")
+    }
+
     /// Push an event onto the event queue
     pub(crate) fn push(&mut self, event: Event) {
-        self.queue
-            .lock()
-            .expect("Lock should not be poisoned")
-            .push_back(event);
+        self.lock().push_back(event);
+        self.ready.notify_all();
+        self.waker.wake();
+    }
+
+    /// Mark that no more events will be pushed, ending the stream once it is empty
+    pub(crate) fn close(&mut self) {
+        self.closed.store(true, Ordering::Release);
+        // Notify whilst holding the lock, so a thread about to wait can't miss it
+        let _queue = self.lock();
+        self.ready.notify_all();
+        self.waker.wake();
     }
 
     /// Returns all events currently on the event queue, which will now be empty.
     pub fn pop_all(&mut self) -> Vec<Event> {
-        self.queue
-            .lock()
+        self.lock().drain(..).collect()
+    }
+
+    /// Returns at most `max` of the oldest events, leaving the rest for later.
+    pub fn pop_up_to(&mut self, max: usize) -> Vec<Event> {
+        let mut queue = self.lock();
+        let count = max.min(queue.len());
+        queue.drain(..count).collect()
+    }
+
+    /// Moves all events currently on the event queue onto the end of `events`, returning how many were moved.
+    /// 
+    /// Reusing the same buffer every tick avoids allocating a new one.
+    pub fn pop_into(&mut self, events: &mut Vec<Event>) -> usize {
+        let mut queue = self.lock();
+        let count = queue.len();
+        events.extend(queue.drain(..));
+        count
+    }
+
+    /// Blocks the current thread until there is an event, or returns `None` once the server has stopped and every event has been taken.
+    /// 
+    /// Must not be called from within an async context, use the `Stream` instead.
+    pub fn wait(&mut self) -> Option<Event> {
+        let closed = &self.closed;
+        self.ready
+            .wait_while(self.lock(), |queue| queue.is_empty() && !closed.load(Ordering::Acquire))
             .expect("Lock should not be poisoned")
-            .drain(..)
-            .collect()
+            .pop_front()
+    }
+
+    /// Same as wait(), but also returns `None` if there is still no event after the timeout.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        let closed = &self.closed;
+        let (mut queue, _) = self.ready
+            .wait_timeout_while(self.lock(), timeout, |queue| queue.is_empty() && !closed.load(Ordering::Acquire))
+            .expect("Lock should not be poisoned");
+        queue.pop_front()
+    }
+
+    /// Blocking iterator over events as they arrive, which ends once the server has stopped and every event has been taken.
+    /// 
+    /// Must not be used from within an async context, use the `Stream` instead.
+    pub fn iter(&mut self) -> impl Iterator<Item = Event> + '_ {
+        std::iter::from_fn(|| self.wait())
+    }
+}
+
+impl Stream for EventQueue {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        // Register before checking, so a push in between still wakes us
+        self.waker.register(cx.waker());
+
+        // Read closed flag first, so events pushed before closing are never missed
+        let closed = self.closed.load(Ordering::Acquire);
+
+        match self.queue.lock().expect("Lock should not be poisoned").pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::EventQueue;
     use super::Event;
+    use bytes::Bytes;
+    use crate::event::{Identifier, Message, Transport};
 
     #[test]
     fn queue_works() {
         let mut q = EventQueue::default();
 
         // Put three in
-        q.push(Event::Received(0, "Hello world!".as_bytes().to_vec()));
-        q.push(Event::Received(1, "Hello world!!".as_bytes().to_vec()));
-        q.push(Event::Received(2, "Hello world!!!".as_bytes().to_vec()));
+        q.push(Event::Received(Identifier::from(0), Message::new(Transport::WebSocket, Bytes::from_static(b"Hello world!"))));
+        q.push(Event::Received(Identifier::from(1), Message::new(Transport::WebSocket, Bytes::from_static(b"Hello world!!"))));
+        q.push(Event::Received(Identifier::from(2), Message::new(Transport::WebSocket, Bytes::from_static(b"Hello world!!!"))));
 
         let out = q.pop_all();
 
@@ -51,4 +135,52 @@ mod tests {
         // Now get nothing out
         assert!(q.pop_all().is_empty());
     }
+
+    #[tokio::test]
+    async fn stream_works() {
+        use futures_util::StreamExt;
+
+        let mut q = EventQueue::default();
+        let mut stream = q.clone();
+
+        let reader = tokio::spawn(async move {
+            let mut count = 0;
+            while stream.next().await.is_some() {
+                count += 1;
+            }
+            count
+        });
+
+        q.push(Event::Received(Identifier::from(0), Message::new(Transport::WebSocket, Bytes::from_static(b"Hello world!"))));
+        q.push(Event::Received(Identifier::from(1), Message::new(Transport::WebSocket, Bytes::from_static(b"Hello world!!"))));
+        q.close();
+
+        // Stream should end after both events
+        assert_eq!(reader.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn waiting_works() {
+        let mut q = EventQueue::default();
+        let received = |n| Event::Received(Identifier::from(n), Message::new(Transport::WebSocket, Bytes::from_static(b"Hello world!")));
+
+        // Nothing arrives in time
+        assert!(q.wait_timeout(Duration::from_millis(10)).is_none());
+
+        (0..3).for_each(|n| q.push(received(n)));
+        assert_eq!(q.pop_up_to(2).len(), 2);
+
+        let mut buffer = Vec::new();
+        assert_eq!(q.pop_into(&mut buffer), 1);
+        assert!(matches!(buffer[..], [Event::Received(id, _)] if id == Identifier::from(2)));
+
+        // A blocked thread is woken by pushes, and its iterator ends once closed
+        let mut reader = q.clone();
+        let thread = std::thread::spawn(move || reader.iter().count());
+        q.push(received(3));
+        q.push(received(4));
+        q.close();
+        assert_eq!(thread.join().unwrap(), 2);
+        assert!(q.wait().is_none());
+    }
 }
\ No newline at end of file
@@ -1,110 +1,281 @@
 //! Job
+//! - Perform the TLS handshake, if configured
 //! - Maintain a websocket connection
 //! - Hold handle to webrtc connection actor
 //! - Emit notification if failure of any of the above
+//! - Ping the client, and emit notification if it stops responding
+//! - Measure round-trip times from pings over the websocket and first data channel
 //! - Handle sending messages
 //! 
 //! Some subtleties:
 //! - Uses binary message types for application messages
 //! - Uses utf8 text message types for webrtc signalling (ICE candidates etc.)
+//! - Close requests skip the command channel, so a full channel can't lose them
 
 use log::{info, warn};
+use bytes::Bytes;
+use std::{net::SocketAddr, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, MutexGuard}, time::Duration};
 use tokio_tungstenite::WebSocketStream;
-use tokio::{net::TcpStream, select, sync::mpsc};
+use tokio::{net::TcpStream, select, sync::{mpsc, Notify}, task::JoinHandle, time::{self, Instant, Interval}};
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
-use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http::{header, StatusCode}, protocol::{frame::coding::CloseCode, CloseFrame}, Error as WebSocketError, Message as WebSocketMessage};
 
-use crate::{event::Identifier, server::webrtc::RTCHandle};
+use crate::{auth::AuthDecision, config::{Reliability, ReliableOverflow, ServerConfig, UnreliableOverflow}, event::{Backpressure, CloseReason, ConnectFailure, ConnectionState, Identifier, Message, Transport, TransportMode}, info::{CandidatePair, ConnectionInfo, Rtt, RttEstimate}, server::webrtc::RTCHandle};
 
-use super::webrtc::{RTCEvent, RtcApiHandle};
+use super::{outbound::{Evict, Outbound, Overflow}, stream::Stream, webrtc::{RTCEvent, RtcApiHandle}};
+
+/// How often to check whether the client is keeping up with its outbound queues
+const BACKLOG_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Start of the payload of each ping, followed by when it was sent and a closing brace
+const PING_PREFIX: &str = r#"{"type":"ping","sent":"#;
 
 /// Events emitted by the connection actor
 #[derive(Debug)]
 pub enum ConnectionEvent {
     /// Signals both websocket + webrtc connection is ready to send/receive messages.
-    ConnectionEstablished, 
-    ConnectionTerminated,
-    MessageReceived(Vec<u8>),
+    ConnectionEstablished(Box<ConnectionInfo>),
+    /// Signals a connection which fell back to the websocket alone now has its webrtc connection too.
+    UpgradedToWebRtc(Option<CandidatePair>),
+    /// Signals the client has started or stopped falling behind on the messages sent to it.
+    Backpressure(Backpressure),
+    ConnectionTerminated(CloseReason),
+    /// Signals the connection failed before it was established.
+    ConnectionFailed(ConnectFailure),
+    /// Signals the connection has reached another stage of being established.
+    StateChanged(ConnectionState),
+    /// Signals the round-trip times have been updated from a pong.
+    RoundTrip(Rtt),
+    MessageReceived(Message),
 }
 
 /// Messages accepted by the connection actor
 enum ConnectionHandleMessage {
-    SendReliable(Vec<u8>),
-    SendUnreliable(Vec<u8>),
-    ReceiveApplicationMessage(Vec<u8>),
+    Send(Outgoing),
+    ReceiveApplicationMessage(Message),
     ReceiveSignalling(String),
-    ReceiveWebSocketClose,
-    HandleWebRTCEvent(RTCEvent)
+    Terminate(CloseReason),
+    HandleWebRTCEvent(RTCEvent),
+}
+
+/// A message for the client, which is held until the connection opens if sent before then
+enum Outgoing {
+    Reliable(Bytes),
+    /// On the data channel at this index of the configuration
+    DataChannel(usize, Bytes),
 }
 
 /// Handle to the connection
 pub struct ConnectionHandle {
-    sender: mpsc::Sender<ConnectionHandleMessage>
+    sender: mpsc::Sender<ConnectionHandleMessage>,
+    queues: Arc<Queues>,
+    config: Arc<ServerConfig>,
 }
 
 impl ConnectionHandle {
 
     /// Spawn a connection actor to service a TcpStream and establish a WebRTC data channel.
-    pub fn new(id: Identifier, emit: mpsc::Sender<(Identifier, ConnectionEvent)>, stream: TcpStream, api: RtcApiHandle) -> Self {
-        let (sender, mut receiver) = mpsc::channel(1024);
+    pub fn new(id: Identifier, emit: mpsc::Sender<(Identifier, ConnectionEvent)>, stream: TcpStream, remote_addr: SocketAddr, api: RtcApiHandle, config: Arc<ServerConfig>) -> Self {
+        let (sender, mut receiver) = mpsc::channel(config.connection_capacity);
+        let queues = Arc::new(Queues::new(&config));
+        let queues_cloned = Arc::clone(&queues);
+        let config_cloned = Arc::clone(&config);
 
         tokio::spawn(async move {
-            // Perform handshake
-            let ws_stream = match tokio_tungstenite::accept_async(stream).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    warn!("Failed websocket handshake: {}", err);
-                    return;
-                },
+            let queues = queues_cloned;
+
+            // Details of the connection, filled in from the upgrade request
+            let mut info = ConnectionInfo {
+                remote_addr,
+                path: String::new(),
+                query: None,
+                headers: Vec::new(),
+                transport_mode: TransportMode::WebRtc,
+                candidate_pair: None,
+                user_data: None,
+            };
+
+            // Perform handshake, unless asked to close first or rejected by the authenticator
+            #[allow(clippy::result_large_err)] // Signature is set by tungstenite
+            let authenticate = |request: &Request, response: Response| {
+                info.path = request.uri().path().to_string();
+                info.query = request.uri().query().map(str::to_string);
+                info.headers = request.headers()
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+                    .collect();
+
+                match config.authenticator.as_ref().map(|authenticator| authenticator.authenticate(&info)) {
+                    Some(AuthDecision::Reject(status, body)) => {
+                        info!("Rejected connection with id={} with status {}", id, status);
+                        Err(rejection(status, body))
+                    },
+                    Some(AuthDecision::Accept(user_data)) => {
+                        info.user_data = user_data;
+                        Ok(response)
+                    },
+                    None => Ok(response),
+                }
+            };
+            let handshake = async {
+                let stream = Stream::accept(stream, &config).await?;
+                tokio_tungstenite::accept_hdr_async(stream, authenticate).await
+            };
+            let handshake_deadline = Instant::now() + config.handshake_timeout;
+            // Messages sent whilst connecting are held until the connection opens
+            let mut pending = Vec::new();
+            let ws_stream = {
+                tokio::pin!(handshake);
+                loop {
+                    select! {
+                        result = &mut handshake => match result {
+                            Ok(stream) => break stream,
+                            Err(WebSocketError::Http(response)) => {
+                                // Rejected by the authenticator, which has already responded
+                                let _ = emit.send((id, ConnectionEvent::ConnectionFailed(ConnectFailure::Rejected(response.status().as_u16())))).await;
+                                return;
+                            },
+                            Err(err) => {
+                                warn!("Failed websocket handshake: {}", err);
+                                let _ = emit.send((id, ConnectionEvent::ConnectionFailed(ConnectFailure::HandshakeFailed(err.to_string())))).await;
+                                return;
+                            },
+                        },
+                        _ = queues.closing.notified() => if let Some((_, reason)) = queues.take_close() {
+                            let _ = emit.send((id, ConnectionEvent::ConnectionTerminated(reason))).await;
+                            info!("Connection with id={} closed before websocket handshake", id);
+                            return;
+                        },
+                        _ = time::sleep_until(handshake_deadline) => {
+                            warn!("Websocket handshake timed out for connection with id={}", id);
+                            let _ = emit.send((id, ConnectionEvent::ConnectionFailed(ConnectFailure::HandshakeTimeout))).await;
+                            return;
+                        },
+                        message_handle = receiver.recv() => match message_handle {
+                            Some(ConnectionHandleMessage::Send(outgoing)) => if !hold(&mut pending, outgoing, &queues, &config, id) && config.reliable_overflow == ReliableOverflow::Disconnect {
+                                warn!("Disconnecting connection with id={}, too many messages are waiting for it to open", id);
+                                let _ = emit.send((id, ConnectionEvent::ConnectionFailed(ConnectFailure::Closed(CloseReason::SlowConsumer)))).await;
+                                return;
+                            },
+                            Some(_) => {},
+                            None => {
+                                info!("Connection with id={} closed before websocket handshake", id);
+                                return;
+                            },
+                        },
+                    }
+                }
             };
 
             // Split ownership of sender and receiver
             let (ws_sink, mut ws_stream) = ws_stream.split();
 
             // Create channel for webrtc actor
-            let (sender_rtc, mut receiver_rtc) = mpsc::channel(1024);
+            let (sender_rtc, mut receiver_rtc) = mpsc::channel(config.connection_capacity);
 
             // Create webrtc actor
-            let actor_rtc = RTCHandle::new(sender_rtc, api);
+            let actor_rtc = RTCHandle::new(sender_rtc, api, Arc::clone(&config), queues.data_channels.clone());
 
             // Create actor
-            let mut actor = Actor::new(id, emit, ws_sink, actor_rtc);
+            let fallback_deadline = config.webrtc_fallback_timeout.map(|timeout| Instant::now() + timeout);
+            let establish_deadline = Instant::now() + config.webrtc_timeout;
+            let shared = Arc::clone(&queues);
+            let mut actor = Actor::new(id, emit, queues, ws_sink, actor_rtc, info, pending, Arc::clone(&config));
+            actor.notify(ConnectionEvent::StateChanged(ConnectionState::Upgraded)).await;
+
+            // Ping periodically, tracking when the client was last heard from
+            let mut heartbeat = config.ping_interval.map(|period| time::interval_at(Instant::now() + period, period));
+            let mut last_seen = Instant::now();
+            let mut backlog_check = time::interval(BACKLOG_CHECK_INTERVAL);
+            let mut data_channel_ping = config.data_channel_ping.map(|period| time::interval_at(Instant::now() + period, period));
 
             info!("Began servicing connection with id={}", id);
 
             // Event loop
             loop {
                 select! {
-                    Some(message_ws) = ws_stream.next() => {
+                    message_ws = ws_stream.next() => {
                         match message_ws {
-                            Ok(message) => {
+                            Some(Ok(message)) => {
                                 info!("Stream gave {:?}", message);
+                                last_seen = Instant::now();
                                 match message {
-                                    WebSocketMessage::Binary(bytes) => actor.handle_message(ConnectionHandleMessage::ReceiveApplicationMessage(bytes.to_vec())),
-                                    WebSocketMessage::Text(text) => actor.handle_message(ConnectionHandleMessage::ReceiveSignalling(text.to_string())),
-                                    WebSocketMessage::Close(_) => {
+                                    WebSocketMessage::Binary(bytes) => actor.handle_message(ConnectionHandleMessage::ReceiveApplicationMessage(Message::new(Transport::WebSocket, bytes))).await,
+                                    WebSocketMessage::Text(text) => actor.handle_message(ConnectionHandleMessage::ReceiveSignalling(text.to_string())).await,
+                                    WebSocketMessage::Close(frame) => {
                                         info!("Received web socket close frame from client");
-                                        actor.handle_message(ConnectionHandleMessage::ReceiveWebSocketClose);
+                                        let reason = match frame {
+                                            Some(frame) => CloseReason::ClientClosed { code: frame.code.into(), reason: frame.reason.to_string() },
+                                            None => CloseReason::ClientClosed { code: CloseCode::Status.into(), reason: String::new() },
+                                        };
+                                        actor.handle_message(ConnectionHandleMessage::Terminate(reason)).await;
                                         break
                                     },
-                                    _ => {} // Ping-pong ignored
+                                    WebSocketMessage::Pong(payload) => actor.websocket_pong(&payload).await,
+                                    _ => {} // Pings only count towards last_seen
                                 }
                             },
-                            Err(err) => {
+                            Some(Err(err)) => {
                                 warn!("Websocket stream error: {}", err);
-                                actor.handle_message(ConnectionHandleMessage::ReceiveWebSocketClose);
+                                actor.handle_message(ConnectionHandleMessage::Terminate(CloseReason::WebSocketError(err.to_string()))).await;
                                 break
                             },
+                            None => {
+                                warn!("Websocket stream ended without a close frame");
+                                actor.handle_message(ConnectionHandleMessage::Terminate(CloseReason::WebSocketError(String::from("Stream ended")))).await;
+                                break
+                            },
+                        }
+                    },
+                    _ = tick(&mut heartbeat) => {
+                        if last_seen.elapsed() > config.pong_timeout {
+                            warn!("Connection with id={} stopped responding", id);
+                            actor.handle_message(ConnectionHandleMessage::Terminate(CloseReason::Timeout)).await;
+                            break
                         }
+                        actor.ping();
+                    },
+                    _ = tick(&mut data_channel_ping) => {
+                        actor.ping_data_channel();
+                    },
+                    _ = sleep_until(fallback_deadline), if actor.mode.is_none() => {
+                        warn!("WebRTC didn't connect in time for connection with id={}", id);
+                        actor.fall_back().await;
+                    },
+                    _ = time::sleep_until(establish_deadline), if actor.mode.is_none() => {
+                        warn!("Gave up connecting WebRTC for connection with id={}", id);
+                        actor.fail(ConnectFailure::WebRtcTimeout);
+                    },
+                    _ = backlog_check.tick() => {
+                        actor.check_backlog().await;
                     },
                     Some(event) = receiver_rtc.recv() => {
                         info!("Got RTCEvent: {:?}", event);
-                        actor.handle_message(ConnectionHandleMessage::HandleWebRTCEvent(event));
+                        actor.handle_message(ConnectionHandleMessage::HandleWebRTCEvent(event)).await;
                     }
+                    _ = shared.overflow.notified() => {
+                        actor.overflowed();
+                    },
+                    _ = shared.closing.notified() => if let Some((frame, reason)) = shared.take_close() {
+                        // Messages sent before the close was asked for still go first
+                        while let Ok(message) = receiver.try_recv() {
+                            actor.handle_message(message).await;
+                        }
+                        info!("Closing connection from server side");
+                        actor.close(frame, reason);
+                        break
+                    },
                     message_handle = receiver.recv() => {
+                        shared.commands_full.store(false, Ordering::Relaxed);
                         match message_handle {
-                            Some(message) => actor.handle_message(message),
-                            None => break,
+                            Some(message) => actor.handle_message(message).await,
+                            None => {
+                                // Handles are dropped straight after asking to close, e.g. when killed
+                                if let Some((frame, reason)) = shared.take_close() {
+                                    actor.close(frame, reason);
+                                }
+                                break
+                            },
                         }
                     },
                     else => {
@@ -112,105 +283,525 @@ impl ConnectionHandle {
                         break;
                     }
                 }
+
+                // E.g. disconnected for falling behind, or the data channel closed
+                if actor.closed {
+                    break
+                }
             }
 
+            // Only reported once everything queued has been written, so the server doesn't stop before then
+            actor.shut_down().await;
             info!("Finished servicing connection with id={}", id);
         });
 
-        Self { sender }
+        Self { sender, queues, config: config_cloned }
     }
 
-    pub fn send_reliable(&mut self, bytes: Vec<u8>) {
-        self.sender.try_send(ConnectionHandleMessage::SendReliable(bytes)).expect("Actor should be alive.");
+    /// Outbound queues of the connection, which can be checked for congestion.
+    pub fn queues(&self) -> Arc<Queues> {
+        Arc::clone(&self.queues)
     }
 
-    pub fn send_unreliable(&mut self, bytes: Vec<u8>) {
-        self.sender.try_send(ConnectionHandleMessage::SendUnreliable(bytes)).expect("Actor should be alive.");
+    pub fn send_reliable(&mut self, bytes: Bytes) {
+        if let Err(err) = self.sender.try_send(ConnectionHandleMessage::Send(Outgoing::Reliable(bytes))) {
+            warn!("Couldn't queue reliable message for connection: {}", err);
+            self.queues.overflowed();
+        }
+    }
+
+    /// Send on the data channel at this index of the configuration.
+    pub fn send_data_channel(&mut self, channel: usize, bytes: Bytes) {
+        if let Err(err) = self.sender.try_send(ConnectionHandleMessage::Send(Outgoing::DataChannel(channel, bytes))) {
+            warn!("Couldn't queue data channel message for connection: {}", err);
+            // Unreliable messages can just be lost
+            if self.config.data_channels.get(channel).is_some_and(|config| config.reliability == Reliability::Reliable) {
+                self.queues.overflowed();
+            }
+        }
+    }
+
+    /// Ask the connection to send a websocket close frame and shut down, it will emit ConnectionTerminated with the reason once done.
+    pub fn close(&mut self, frame: CloseFrame, reason: CloseReason) {
+        self.queues.request_close(frame, reason);
     }
 }
 
-type WsSink = SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>;
+type WsSink = SplitSink<WebSocketStream<Stream>, tokio_tungstenite::tungstenite::Message>;
+
+/// Outbound queues of a connection, shared with `Server` handles so they can see when it is congested.
+pub struct Queues {
+    websocket: Arc<Outbound<SinkMessage>>,
+    // One per data channel, in the order they were configured
+    data_channels: Vec<Arc<Outbound<Bytes>>>,
+    // Set whilst the connection's own channel is too full to take a reliable message, until it takes the next one
+    commands_full: AtomicBool,
+    // Wakes the connection to apply the reliable overflow policy for a message which didn't reach its queue
+    overflow: Notify,
+    // Messages held until the connection opens, and how many it may hold
+    held: AtomicUsize,
+    hold_limit: usize,
+    // Close asked for by the server, kept until the connection wakes to take it
+    close: Mutex<Option<(CloseFrame, CloseReason)>>,
+    closing: Notify,
+}
+
+impl Queues {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            websocket: Arc::new(Outbound::new(config.outbound_limit)),
+            data_channels: config.data_channels.iter().map(|_| Arc::new(Outbound::new(config.outbound_limit))).collect(),
+            commands_full: AtomicBool::new(false),
+            overflow: Notify::new(),
+            held: AtomicUsize::new(0),
+            hold_limit: config.outbound_limit,
+            close: Mutex::new(None),
+            closing: Notify::new(),
+        }
+    }
+
+    pub fn websocket_full(&self) -> bool {
+        self.websocket.is_full() || self.commands_full.load(Ordering::Relaxed)
+    }
+
+    /// False for channels which don't exist.
+    pub fn data_channel_full(&self, channel: usize) -> bool {
+        self.data_channels.get(channel).is_some_and(|queue| queue.is_full()) || self.commands_full.load(Ordering::Relaxed)
+    }
+
+    /// Whether a message sent before the connection opens would have to be dropped.
+    pub fn held_full(&self) -> bool {
+        self.held.load(Ordering::Relaxed) >= self.hold_limit || self.commands_full.load(Ordering::Relaxed)
+    }
+
+    /// A reliable message was dropped before reaching the connection.
+    fn overflowed(&self) {
+        self.commands_full.store(true, Ordering::Relaxed);
+        self.overflow.notify_one();
+    }
+
+    /// Ask the connection to close, keeping the first request if asked more than once.
+    fn request_close(&self, frame: CloseFrame, reason: CloseReason) {
+        self.lock_close().get_or_insert((frame, reason));
+        self.closing.notify_one();
+    }
+
+    fn take_close(&self) -> Option<(CloseFrame, CloseReason)> {
+        self.lock_close().take()
+    }
+
+    fn lock_close(&self) -> MutexGuard<'_, Option<(CloseFrame, CloseReason)>> {
+        self.close.lock().expect("Lock should not be poisoned")
+    }
+
+    /// Messages in the deepest queue, and the most bytes buffered by any data channel.
+    fn backlog(&self) -> (usize, usize) {
+        let depth = self.data_channels.iter().map(|queue| queue.len()).fold(self.websocket.len(), usize::max);
+        let buffered = self.data_channels.iter().map(|queue| queue.buffered()).max().unwrap_or(0);
+        (depth, buffered)
+    }
+
+    /// Lets the tasks writing to each transport finish once they have emptied their queues.
+    fn close(&self) {
+        self.websocket.close();
+        self.data_channels.iter().for_each(|queue| queue.close());
+    }
+}
 
 struct Actor {
     id: Identifier,
     emit: mpsc::Sender<(Identifier, ConnectionEvent)>,
-    send: mpsc::Sender<SinkMessage>,
+    queues: Arc<Queues>,
     rtc: RTCHandle,
+    config: Arc<ServerConfig>,
+    // Details of the connection, handed to the parent actor once established
+    info: ConnectionInfo,
+    // Set once the connection is established, to tell failures to connect apart from dropped connections
+    mode: Option<TransportMode>,
+    // Whether to establish the connection over the websocket alone if webrtc fails to connect
+    fallback: bool,
+    // Messages sent before the connection opened, in the order they were sent
+    pending: Vec<Outgoing>,
+    // Whether the client was last reported as falling behind
+    behind: bool,
+    // Set once the connection has terminated, after which it should stop being serviced
+    closed: bool,
+    // Final event for the parent actor, sent once the transports have been shut down
+    outcome: Option<ConnectionEvent>,
+    // Writes queued messages to the websocket
+    sink_task: JoinHandle<()>,
+    // Pings carry the time since this, so their pongs can be timed
+    started: Instant,
+    rtt: Rtt,
 }
 
 impl Actor {
-    pub fn new(id: Identifier, emit: mpsc::Sender<(Identifier, ConnectionEvent)>, sink: WsSink, rtc: RTCHandle) -> Self {
-        let send = start_sink_task(sink);
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(id: Identifier, emit: mpsc::Sender<(Identifier, ConnectionEvent)>, queues: Arc<Queues>, sink: WsSink, rtc: RTCHandle, info: ConnectionInfo, pending: Vec<Outgoing>, config: Arc<ServerConfig>) -> Self {
+        let started = Instant::now();
+        let sink_task = start_sink_task(sink, Arc::clone(&queues.websocket), started);
+        let fallback = config.webrtc_fallback_timeout.is_some();
 
-        Self { id, emit, send, rtc }
+        Self { id, emit, queues, rtc, config, info, mode: None, fallback, pending, behind: false, closed: false, outcome: None, sink_task, started, rtt: Rtt::default() }
     }
 
-    pub fn handle_message(&mut self, message: ConnectionHandleMessage) {
+    pub async fn handle_message(&mut self, message: ConnectionHandleMessage) {
         match message {
-            ConnectionHandleMessage::SendReliable(bytes) => {
-                self.send.try_send(SinkMessage::Data(bytes)).expect("Sender task should be alive.");
+            ConnectionHandleMessage::Send(outgoing) if self.mode.is_none() => {
+                if !hold(&mut self.pending, outgoing, &self.queues, &self.config, self.id) && self.config.reliable_overflow == ReliableOverflow::Disconnect {
+                    self.overflowed();
+                }
             },
-            ConnectionHandleMessage::SendUnreliable(bytes) => {
-                self.rtc.send_message(bytes)
+            ConnectionHandleMessage::Send(outgoing) => {
+                self.send(outgoing);
             },
             ConnectionHandleMessage::ReceiveSignalling(message) => {
                 self.rtc.receive_signalling_message(message);
             },
-            ConnectionHandleMessage::ReceiveApplicationMessage(bytes) => {
-                self.emit.try_send((self.id, ConnectionEvent::MessageReceived(bytes))).expect("Parent actor should be alive.");
+            ConnectionHandleMessage::ReceiveApplicationMessage(message) => {
+                self.notify(ConnectionEvent::MessageReceived(message)).await;
             },
-            ConnectionHandleMessage::ReceiveWebSocketClose => {
-                self.emit.try_send((self.id, ConnectionEvent::ConnectionTerminated)).expect("Parent actor should be alive.");
+            ConnectionHandleMessage::Terminate(reason) => {
+                self.terminate(reason);
             }
             ConnectionHandleMessage::HandleWebRTCEvent(event) => {
-                self.handle_webrtc_event(event);
+                self.handle_webrtc_event(event).await;
+            },
+        }
+    }
+
+    /// Sends a close frame down the websocket and notifies the parent actor, the caller should stop servicing the connection afterwards.
+    pub fn close(&mut self, frame: CloseFrame, reason: CloseReason) {
+        if !self.closed {
+            self.queues.websocket.push_control(SinkMessage::Close(frame));
+        }
+        // Closed by the server, so not a failure even if the connection never opened
+        self.finish(ConnectionEvent::ConnectionTerminated(reason));
+    }
+
+    /// Gives up on a connection which hasn't been established, telling the client why.
+    pub fn fail(&mut self, failure: ConnectFailure) {
+        if !self.closed {
+            let frame = CloseFrame { code: CloseCode::Error, reason: failure.to_string().into() };
+            self.queues.websocket.push_control(SinkMessage::Close(frame));
+        }
+        self.finish(ConnectionEvent::ConnectionFailed(failure));
+    }
+
+    /// Notifies the parent actor that the connection has terminated, or failed if it was never established.
+    fn terminate(&mut self, reason: CloseReason) {
+        let event = match self.mode {
+            Some(_) => ConnectionEvent::ConnectionTerminated(reason),
+            None => ConnectionEvent::ConnectionFailed(ConnectFailure::Closed(reason)),
+        };
+        self.finish(event);
+    }
+
+    /// Records the final event for the parent actor, unless there already is one.
+    fn finish(&mut self, event: ConnectionEvent) {
+        if !self.closed {
+            self.closed = true;
+            self.outcome = Some(event);
+        }
+    }
+
+    /// Waits for queued messages (and any close frame) to be written and the peer connection to close, then sends the final event.
+    ///
+    /// Gives up after `pong_timeout`, as a client which takes nothing for that long would have been timed out anyway.
+    pub async fn shut_down(&mut self) {
+        self.queues.close();
+        let flushed = async {
+            let _ = (&mut self.sink_task).await;
+            self.rtc.close().await;
+        };
+        if time::timeout(self.config.pong_timeout, flushed).await.is_err() {
+            warn!("Gave up flushing messages for connection with id={}", self.id);
+            self.sink_task.abort();
+        }
+
+        if let Some(event) = self.outcome.take() {
+            self.notify(event).await;
+        }
+    }
+
+    /// Emits an event to the parent actor, waiting for room in its queue.
+    async fn notify(&self, event: ConnectionEvent) {
+        // The parent actor only goes away once the server has stopped, which also drops this connection
+        let _ = self.emit.send((self.id, event)).await;
+    }
+
+    /// Establish the connection over the websocket alone, if it hasn't been established yet.
+    pub async fn fall_back(&mut self) {
+        if self.mode.is_none() {
+            self.establish(TransportMode::WebSocketOnly, None).await;
+        }
+    }
+
+    async fn establish(&mut self, mode: TransportMode, candidate_pair: Option<CandidatePair>) {
+        self.mode = Some(mode);
+        self.info.transport_mode = mode;
+        self.info.candidate_pair = candidate_pair;
+        let info = Box::new(self.info.clone());
+        self.notify(ConnectionEvent::ConnectionEstablished(info)).await;
+
+        // Flush messages sent whilst connecting, now that there is somewhere to send them
+        self.queues.held.store(0, Ordering::Relaxed);
+        for outgoing in std::mem::take(&mut self.pending) {
+            self.send(outgoing);
+        }
+    }
+
+    fn send(&mut self, outgoing: Outgoing) {
+        match (outgoing, self.mode) {
+            (Outgoing::Reliable(bytes), _) => self.send_reliable_websocket(bytes),
+            // Reliable channels keep their guarantee over the websocket
+            (Outgoing::DataChannel(channel, bytes), Some(TransportMode::WebSocketOnly)) => match self.config.data_channels.get(channel) {
+                Some(config) if config.reliability == Reliability::Reliable => self.send_reliable_websocket(bytes),
+                _ => self.send_unreliable_websocket(bytes),
+            },
+            (Outgoing::DataChannel(channel, bytes), _) => self.send_data_channel(channel, bytes),
+        }
+    }
+
+    fn send_reliable_websocket(&mut self, bytes: Bytes) {
+        if self.queues.websocket.push(SinkMessage::Data(bytes), Overflow::Reject).is_err() {
+            self.overflowed();
+        }
+    }
+
+    /// Applies the overflow policy for the channel's reliability if its queue is full.
+    fn send_data_channel(&mut self, channel: usize, bytes: Bytes) {
+        let (Some(queue), Some(config)) = (self.queues.data_channels.get(channel), self.config.data_channels.get(channel)) else {
+            warn!("Dropping message for unknown data channel {}", channel);
+            return;
+        };
+
+        if config.reliability == Reliability::Reliable {
+            if queue.push(bytes, Overflow::Reject).is_err() {
+                self.overflowed();
             }
+        } else if queue.push(bytes, self.unreliable_overflow()).is_err() {
+            warn!("Dropped an unreliable message for data channel '{}' of connection with id={}, its queue is full", config.label, self.id);
+        }
+    }
+
+    /// Sends over the websocket in place of a data channel, only using up to half of the websocket's queue.
+    fn send_unreliable_websocket(&mut self, bytes: Bytes) {
+        let limit = (self.config.outbound_limit / 2).max(1);
+        if self.queues.websocket.push_within(SinkMessage::Unreliable(bytes), limit, self.unreliable_overflow()).is_err() {
+            warn!("Dropped an unreliable message for congested websocket of connection with id={}", self.id);
+        }
+    }
+
+    fn unreliable_overflow(&self) -> Overflow {
+        match self.config.unreliable_overflow {
+            UnreliableOverflow::DropNewest => Overflow::DropNewest,
+            UnreliableOverflow::DropOldest => Overflow::DropOldest,
+        }
+    }
+
+    /// A reliable message didn't fit in its queue, so apply the reliable overflow policy.
+    pub fn overflowed(&mut self) {
+        match self.config.reliable_overflow {
+            ReliableOverflow::Error => warn!("Dropped a reliable message for connection with id={}, its queue is full", self.id),
+            ReliableOverflow::Disconnect => {
+                warn!("Disconnecting connection with id={}, which has fallen too far behind", self.id);
+                // Nothing queued will reach the client in time, so skip straight to the close frame
+                self.queues.websocket.clear();
+                let frame = CloseFrame { code: CloseCode::Policy, reason: "Too slow to receive messages".into() };
+                self.close(frame, CloseReason::SlowConsumer);
+            },
         }
     }
 
-    pub fn handle_webrtc_event(&mut self, event: RTCEvent) {
+    /// Reports the client falling behind or catching up, judged by its deepest queue and fullest data channel buffer.
+    pub async fn check_backlog(&mut self) {
+        if self.mode.is_none() || self.closed {
+            return;
+        }
+
+        // Buffers only otherwise update when sending, so would stay high once nothing more is sent. Read on the next check
+        if self.mode == Some(TransportMode::WebRtc) {
+            self.rtc.sample_buffered();
+        }
+
+        let (depth, buffered) = self.queues.backlog();
+        let (max_depth, max_buffered) = (self.config.slow_consumer_depth, self.config.slow_consumer_buffered);
+        let behind = match self.behind {
+            // Only caught up once well clear of the thresholds, so that it doesn't flap
+            true => depth * 2 >= max_depth || buffered * 2 >= max_buffered,
+            false => depth >= max_depth || buffered >= max_buffered,
+        };
+
+        if behind != self.behind {
+            self.behind = behind;
+            let backpressure = if behind { Backpressure::FellBehind } else { Backpressure::CaughtUp };
+            self.notify(ConnectionEvent::Backpressure(backpressure)).await;
+        }
+    }
+
+    /// Pings over the websocket, timed from when the ping is written rather than queued.
+    pub fn ping(&mut self) {
+        self.queues.websocket.push_control(SinkMessage::Ping);
+    }
+
+    /// Pings over the first data channel, once WebRTC is open.
+    pub fn ping_data_channel(&mut self) {
+        if self.mode == Some(TransportMode::WebRtc) {
+            self.rtc.ping(ping_payload(self.started));
+        }
+    }
+
+    /// Time since the ping was sent, if the payload is one of ours.
+    fn round_trip(&self, payload: &[u8]) -> Option<Duration> {
+        let sent = payload.strip_prefix(PING_PREFIX.as_bytes())?.strip_suffix(b"}")?;
+        let sent = Duration::from_micros(std::str::from_utf8(sent).ok()?.parse().ok()?);
+        Instant::now().checked_duration_since(self.started + sent)
+    }
+
+    pub async fn websocket_pong(&mut self, payload: &[u8]) {
+        if let Some(rtt) = self.round_trip(payload) {
+            self.rtt.websocket = Some(RttEstimate::sample(self.rtt.websocket, rtt));
+            self.notify(ConnectionEvent::RoundTrip(self.rtt)).await;
+        }
+    }
+
+    pub async fn handle_webrtc_event(&mut self, event: RTCEvent) {
         match event {
-            RTCEvent::Opened => {
-                self.emit.try_send((self.id, ConnectionEvent::ConnectionEstablished)).expect("Parent actor should be alive.");
+            RTCEvent::Opened(candidate_pair) => match self.mode {
+                None => self.establish(TransportMode::WebRtc, candidate_pair).await,
+                Some(TransportMode::WebSocketOnly) => {
+                    info!("Upgrading connection with id={} to WebRTC", self.id);
+                    self.mode = Some(TransportMode::WebRtc);
+                    self.notify(ConnectionEvent::UpgradedToWebRtc(candidate_pair)).await;
+                },
+                Some(TransportMode::WebRtc) => {},
             },
+            // The websocket carries on by itself if falling back, or already has
+            RTCEvent::Closed | RTCEvent::Failed if self.mode == Some(TransportMode::WebSocketOnly) => {},
+            RTCEvent::Closed | RTCEvent::Failed if self.mode.is_none() && self.fallback => self.fall_back().await,
             RTCEvent::Closed => {
-                self.emit.try_send((self.id, ConnectionEvent::ConnectionTerminated)).expect("Parent actor should be alive.");
+                self.terminate(CloseReason::DataChannelClosed);
             },
-            RTCEvent::ApplicationMessageReceived(bytes) => {
-                self.emit.try_send((self.id, ConnectionEvent::MessageReceived(bytes))).expect("Parent actor should be alive.");
+            RTCEvent::Failed => {
+                // ICE gives up after ice_failed_timeout without connectivity, so an opened connection has gone silent
+                let reason = if self.mode.is_some() { CloseReason::Timeout } else { CloseReason::IceFailure };
+                self.terminate(reason);
+            },
+            // Pings echoed by the client are only used for timing
+            RTCEvent::ApplicationMessageReceived(message) => match self.config.data_channel_ping.and_then(|_| self.round_trip(&message.payload)) {
+                Some(rtt) => {
+                    self.rtt.data_channel = Some(RttEstimate::sample(self.rtt.data_channel, rtt));
+                    self.notify(ConnectionEvent::RoundTrip(self.rtt)).await;
+                },
+                None => self.notify(ConnectionEvent::MessageReceived(message)).await,
             },
             RTCEvent::EmitSignallingMessage(message) => {
-                self.send.try_send(SinkMessage::Signalling(message)).expect("Sender task should be alive.");
+                self.queues.websocket.push_control(SinkMessage::Signalling(message));
+            },
+            // Once open, the connection stays that way whichever transport it uses
+            RTCEvent::StateChanged(state) => if self.mode.is_none() {
+                self.notify(ConnectionEvent::StateChanged(state)).await;
             },
         }
     }
 }
 
+impl Drop for Actor {
+    fn drop(&mut self) {
+        self.queues.close();
+    }
+}
+
 enum SinkMessage {
-    Data(Vec<u8>),
+    Data(Bytes),
+    /// Sent in place of a data channel, so it can be dropped to make room
+    Unreliable(Bytes),
     Signalling(String),
+    /// Payload is filled in as it is written, and echoed back in the pong
+    Ping,
+    Close(CloseFrame),
 }
 
-/// Spawns a task whose job is to forward messages into the provided sink, which is only possible in an async context.
-/// 
-/// Task finishes when returns when all senders are dropped.
-fn start_sink_task(mut sink: WsSink) -> mpsc::Sender<SinkMessage> {
-    let (sender, mut receiver) = mpsc::channel::<SinkMessage>(1024);
+impl Evict for SinkMessage {
+    fn evictable(&self) -> bool {
+        matches!(self, SinkMessage::Unreliable(_))
+    }
+}
 
+/// Spawns a task whose job is to forward messages from the queue into the provided sink, which is only possible in an async context.
+/// 
+/// Task finishes once the queue is closed and empty, or after sending a close frame.
+fn start_sink_task(mut sink: WsSink, queue: Arc<Outbound<SinkMessage>>, started: Instant) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Some(message) = receiver.recv().await {
+        while let Some(message) = queue.pop().await {
             let result = match message {
-                SinkMessage::Data(bytes) => sink.send(WebSocketMessage::Binary(bytes::Bytes::copy_from_slice(&bytes))).await,
+                SinkMessage::Data(bytes) | SinkMessage::Unreliable(bytes) => sink.send(WebSocketMessage::Binary(bytes)).await,
                 SinkMessage::Signalling(message) => sink.send(WebSocketMessage::text(message)).await,
+                SinkMessage::Ping => sink.send(WebSocketMessage::Ping(ping_payload(started).into())).await,
+                SinkMessage::Close(frame) => {
+                    if let Err(err) = sink.send(WebSocketMessage::Close(Some(frame))).await {
+                        warn!("Error sending close frame to websocket sink : {:?}", err);
+                    }
+                    break;
+                },
             };
                 
             if let Err(err) = result {
                 warn!("Error sending to websocket sink : {:?}", err);
             }
         }
-    });
+    })
+}
+
+/// Holds a message until the connection opens, dropping it if too many are already waiting.
+///
+/// Returns false if a reliable message was dropped, so the caller can apply the reliable overflow policy.
+fn hold(pending: &mut Vec<Outgoing>, outgoing: Outgoing, queues: &Queues, config: &ServerConfig, id: Identifier) -> bool {
+    if pending.len() < config.outbound_limit {
+        pending.push(outgoing);
+        queues.held.store(pending.len(), Ordering::Relaxed);
+        return true;
+    }
 
-    sender
+    warn!("Dropped a message for connection with id={}, too many are waiting for it to open", id);
+    match outgoing {
+        Outgoing::Reliable(_) => false,
+        Outgoing::DataChannel(channel, _) => config.data_channels.get(channel).is_none_or(|config| config.reliability != Reliability::Reliable),
+    }
+}
+
+/// Payload of a ping sent now, holding the time since the connection started.
+fn ping_payload(started: Instant) -> String {
+    format!("{}{}}}", PING_PREFIX, started.elapsed().as_micros())
+}
+
+/// HTTP response sent to a client rejected by the authenticator, using 400 Bad Request if the status isn't an error.
+fn rejection(status: u16, body: String) -> ErrorResponse {
+    let status = StatusCode::from_u16(status)
+        .ok()
+        .filter(|status| status.is_client_error() || status.is_server_error())
+        .unwrap_or(StatusCode::BAD_REQUEST);
+
+    let length = body.len();
+    let mut response = ErrorResponse::new(Some(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_LENGTH, length.into());
+    response
 }
 
+/// Completes at the deadline, or never if there isn't one.
+pub(super) async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
 
+/// Completes on the next tick of the interval, or never if there isn't one.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; },
+        None => std::future::pending().await,
+    }
+}
@@ -1,20 +1,30 @@
 //! Websocket actor
+//! - Run as a task on a tokio runtime, either provided by the caller (AsyncServer) or owned by its own OS thread (Server)
 //! - Listen for new connections
 //! - Establish an ID
 //! - Establish a webrtc actor/connection per websocket connection
-//! - Kill the connection if asked
+//! - Kill the connection if asked, sending the client a close frame
 //! - Notify that connection is dead
+//! - Send one payload to many connections, whether all of them, a room or a chosen few
+//! - Report connections which fall behind on the messages sent to them
+//! - Close every connection and stop when shutting down
 
-use log::info;
+use bytes::Bytes;
+use log::{info, warn};
 use webrtc::RtcApiHandle;
-use std::collections::HashMap;
-use tokio::{net::{TcpListener, TcpStream}, runtime::Builder, select, sync::mpsc};
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}};
+use tokio::{net::{TcpListener, TcpStream, UdpSocket}, runtime::Builder, select, sync::mpsc::{self, error::TrySendError}, task};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
 
 use connection::{ConnectionEvent, ConnectionHandle};
-use crate::{event::{Event, Identifier}, queue::EventQueue};
+use registry::Registry;
+use crate::{config::{PreOpenBuffer, Reliability, ReliableOverflow, RuntimeFlavour, ServerBuilder, ServerConfig}, error::ServerError, event::{CloseReason, ConnectFailure, ConnectionState, Event, Identifier, TransportMode}, info::{ConnectionInfo, Rtt}, queue::EventQueue};
 
 mod webrtc;
 mod connection;
+mod registry;
+mod stream;
+mod outbound;
 
 
 enum ActorMessage {
@@ -23,148 +33,687 @@ enum ActorMessage {
     */
 
     HandleConnectionEvent(Identifier, ConnectionEvent),
-    HandleNewStream(TcpStream),
+    HandleNewStream(TcpStream, SocketAddr),
 
     /*
         Commands given to the actor 
     */
 
-    Kill(Identifier),
-    SendReliable(Identifier, Vec<u8>),
-    SendUnreliable(Identifier, Vec<u8>),
-    Broadcast(Vec<u8>)
+    Kill(Identifier, CloseFrame),
+    SendReliable(Identifier, Bytes),
+    /// Send on the data channel at this index of the configuration
+    SendDataChannel(Identifier, usize, Bytes),
+    /// Send to each open connection in the targets, sharing the buffer between them
+    Multicast(Targets, Route, Bytes),
+    Shutdown(Option<Instant>),
 }
 
-/// Handle to a websocket + webrtc server, used for sending messages and killing active connections.
+/// How a message should be sent down a connection.
+#[derive(Clone, Copy)]
+enum Route {
+    WebSocket,
+    /// The data channel at this index of the configuration
+    DataChannel(usize),
+}
+
+/// Which connections a multicast is sent to, those which aren't open are skipped unless configured to hold messages until they are.
+enum Targets {
+    All,
+    /// Members of a room, optionally skipping one of them
+    Room(String, Option<Identifier>),
+    Only(Vec<Identifier>),
+}
+
+impl Route {
+    /// Whether connections which aren't open yet hold messages on this route until they are, rather than refusing them.
+    fn held_until_open(self, config: &ServerConfig) -> bool {
+        match config.pre_open_buffer {
+            PreOpenBuffer::None => false,
+            PreOpenBuffer::Reliable => matches!(self, Route::WebSocket),
+            PreOpenBuffer::All => true,
+        }
+    }
+
+    fn send(self, handle: &mut ConnectionHandle, bytes: Bytes) {
+        match self {
+            Route::WebSocket => handle.send_reliable(bytes),
+            Route::DataChannel(channel) => handle.send_data_channel(channel, bytes),
+        }
+    }
+}
+
+/// Handle to a websocket + webrtc server running on an existing tokio runtime, used for sending messages and killing active connections.
+/// 
+/// Sending waits for space in the server's command queue. Events can be consumed by polling the `EventQueue` as a `Stream`.
+/// 
+/// Payloads are anything which converts into `Bytes`, such as a `Vec<u8>` or a static slice, without being copied.
+/// Sends fail with `ServerError::Congested` when the connection's outbound queue is full, if configured with `ReliableOverflow::Error`.
 /// 
 /// Can be freely cloned, will point to the same instance.
 #[derive(Clone)]
-pub struct Server {
-    sender: mpsc::Sender<ActorMessage>
+pub struct AsyncServer {
+    sender: mpsc::Sender<ActorMessage>,
+    registry: Registry,
+    config: Arc<ServerConfig>,
+    local_addr: SocketAddr,
+    task: Arc<Mutex<Option<task::JoinHandle<()>>>>,
 }
 
-impl Server {
-    /// Create new server, which will be spawned on a new OS thread.
-    pub fn new(listen_addr: &str) -> (Self, EventQueue) {
-        let listen_addr = listen_addr.to_string();
+impl AsyncServer {
+    /// Create new server from a configuration, which will be spawned as a task on the current tokio runtime.
+    /// 
+    /// The `runtime` option of the configuration is ignored. Fails if either address can't be bound.
+    pub async fn new(config: ServerConfig) -> Result<(Self, EventQueue), ServerError> {
+        if let Some(level) = config.log_level {
+            log::set_max_level(level);
+        }
+
+        config.validate()?;
+        webrtc::validate_ice_servers(&config)?;
+        let config = Arc::new(config);
 
         // Create a message queue
         let queue = EventQueue::default();
 
+        // Create shared view of connections
+        let registry = Registry::default();
+
         // Channel for the handle
-        let (sender, mut receiver) = mpsc::channel(1024);
+        let (sender, mut receiver) = mpsc::channel(config.command_capacity);
 
         // Channel for active connections to emit events
-        let (sender_connection, mut receiver_connection) = mpsc::channel::<(Identifier, ConnectionEvent)>(1024);
+        let (sender_connection, mut receiver_connection) = mpsc::channel::<(Identifier, ConnectionEvent)>(config.event_capacity);
 
-        // Create an async runtime
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
+        // Bind sockets up front, so that failures can be returned to the caller
+        let listener = TcpListener::bind(&config.tcp_addr).await.map_err(ServerError::Bind)?;
+        let socket = UdpSocket::bind(&config.udp_addr).await.map_err(ServerError::Bind)?;
+        let local_addr = listener.local_addr().map_err(ServerError::Bind)?;
+        info!("Websockets server bound to {}", local_addr);
 
-        // Create an OS thread, and then start the event loop on our runtime
-        let queue_cloned = queue.clone();
-        std::thread::spawn(move || {
-            rt.block_on(async move {
-                // Intialse WebRTC API actor
-                let api = RtcApiHandle::new(&listen_addr);
-
-                // Create server actor
-                let mut actor = Actor::new(sender_connection, queue_cloned, api);
-
-                // Create websocket server
-                let listener = TcpListener::bind(&listen_addr).await.expect("Should be able to bind to listen_addr");
-                info!("Websockets server bound to {}", listen_addr);
-
-                // Event loop : Poll actor messages, tcp server and connection events.
-                loop {
-                    select! {
-                        handle = receiver.recv() => {
-                            match handle {
-                                Some(message) => actor.handle_message(message),
-                                None => break, // Kill actor and all connections
-                            };
-                        },
-                        Ok((stream, _)) = listener.accept() => {
-                            actor.handle_message(ActorMessage::HandleNewStream(stream));
-                        },
-                        Some((id, connection_event)) = receiver_connection.recv() => {
-                            actor.handle_message(ActorMessage::HandleConnectionEvent(id, connection_event));
-                        },
-                        else => {}
-                    }
+        // Start the event loop as a task
+        let mut queue_cloned = queue.clone();
+        let registry_cloned = registry.clone();
+        let config_cloned = Arc::clone(&config);
+        let task = tokio::spawn(async move {
+            let config = config_cloned;
+
+            // Intialse WebRTC API actor
+            let api = RtcApiHandle::new(socket, Arc::clone(&config));
+
+            // Create server actor
+            let mut actor = Actor::new(sender_connection, queue_cloned.clone(), registry_cloned, api, Arc::clone(&config));
+
+            // Event loop : Poll actor messages, tcp server and connection events.
+            while !actor.is_drained() {
+                let deadline = actor.drain_deadline;
+
+                select! {
+                    handle = receiver.recv() => {
+                        match handle {
+                            Some(message) => actor.handle_message(message),
+                            None => break, // Kill actor and all connections
+                        };
+                    },
+                    Ok((stream, remote_addr)) = listener.accept(), if !actor.draining => {
+                        actor.handle_message(ActorMessage::HandleNewStream(stream, remote_addr));
+                    },
+                    Some((id, connection_event)) = receiver_connection.recv() => {
+                        actor.handle_message(ActorMessage::HandleConnectionEvent(id, connection_event));
+                    },
+                    _ = connection::sleep_until(deadline.map(Into::into)) => {
+                        warn!("Shutdown timed out, abandoning remaining connections");
+                        actor.abandon_connections();
+                    },
+                    else => {}
                 }
-            })
+            }
+
+            // No more events will be pushed
+            queue_cloned.close();
+            info!("Server stopped");
         });
 
-        (Server { sender }, queue)
+        let server = AsyncServer {
+            sender,
+            registry,
+            config,
+            local_addr,
+            task: Arc::new(Mutex::new(Some(task))),
+        };
+
+        Ok((server, queue))
+    }
+
+    /// Address the websocket listener is bound to, useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Details of an open connection, the same as those reported with its Event::Open.
+    pub fn connection_info(&self, id: Identifier) -> Result<ConnectionInfo, ServerError> {
+        self.registry.info(id)
+    }
+
+    /// The stage a connection has reached, see `Server::state`.
+    pub fn state(&self, id: Identifier) -> Result<ConnectionState, ServerError> {
+        self.registry.state(id)
+    }
+
+    /// Round-trip times measured for a connection, see `Server::rtt`.
+    pub fn rtt(&self, id: Identifier) -> Result<Rtt, ServerError> {
+        self.registry.rtt(id)
+    }
+
+    /// Signal to kill a connection with a given identifier, which may still be connecting. See `Server::kill`.
+    pub async fn kill(&self, id: Identifier) -> Result<(), ServerError> {
+        self.kill_with(id, CloseCode::Normal.into(), "").await
+    }
+
+    /// Same as kill(), but the client's close frame has this code and reason. See `Server::kill_with`.
+    pub async fn kill_with(&self, id: Identifier, code: u16, reason: &str) -> Result<(), ServerError> {
+        let frame = close_frame(code, reason)?;
+        self.registry.check_known(id)?;
+        self.send_to_actor(ActorMessage::Kill(id, frame)).await
+    }
+
+    /// Send a message down a connection with the given identifier. Uses websockets as a reliable communication protocol.
+    pub async fn send_reliable(&self, id: Identifier, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.check_room(id, Route::WebSocket)?;
+        self.send_to_actor(ActorMessage::SendReliable(id, bytes.into())).await
+    }
+
+    /// Send a message down a connection with the given identifier. Uses the first configured webrtc datachannel, which is unreliable by default.
+    pub async fn send_unreliable(&self, id: Identifier, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.check_room(id, Route::DataChannel(0))?;
+        self.send_to_actor(ActorMessage::SendDataChannel(id, 0, bytes.into())).await
     }
 
-    /// Signal to kill a connection with a given identifier. 
+    /// Send a message down the webrtc datachannel with the given label, as configured in `ServerConfig::data_channels`.
+    pub async fn send(&self, id: Identifier, channel: &str, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        let channel = self.channel_index(channel)?;
+        self.check_room(id, Route::DataChannel(channel))?;
+        self.send_to_actor(ActorMessage::SendDataChannel(id, channel, bytes.into())).await
+    }
+
+    /// Broadcast a message reliably down all open connections, and those holding messages until they open (see `PreOpenBuffer`).
+    pub async fn broadcast(&self, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::All, Route::WebSocket, bytes.into())).await
+    }
+
+    /// Broadcast a message down the first data channel of all open connections, as with `send_unreliable`. Also held by connections which haven't opened if `PreOpenBuffer::All` is set.
+    pub async fn broadcast_unreliable(&self, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::All, Route::DataChannel(0), bytes.into())).await
+    }
+
+    /// Send a message reliably down each of the given connections, skipping any which aren't open (unless held, see `ServerConfig::pre_open_buffer`).
+    pub async fn send_many(&self, ids: &[Identifier], bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::Only(ids.to_vec()), Route::WebSocket, bytes.into())).await
+    }
+
+    /// Send a message down the first data channel of each of the given connections, skipping any which aren't open (unless held, see `ServerConfig::pre_open_buffer`).
+    pub async fn send_many_unreliable(&self, ids: &[Identifier], bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::Only(ids.to_vec()), Route::DataChannel(0), bytes.into())).await
+    }
+
+    /// Send a message reliably down every open connection the filter accepts.
     /// 
-    /// The event queue should receive an Event::Closed(id) with the same identifier to confirm the action.
-    pub fn kill(&mut self, id: Identifier) {
-        self.sender.blocking_send(ActorMessage::Kill(id)).expect("Actor should be alive");
+    /// The filter is called whilst holding the server's view of connections, so must not call back into the server.
+    pub async fn multicast_reliable(&self, filter: impl FnMut(Identifier, &ConnectionInfo) -> bool, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        let ids = self.registry.open_matching(filter);
+        self.send_to_actor(ActorMessage::Multicast(Targets::Only(ids), Route::WebSocket, bytes.into())).await
+    }
+
+    /// Send a message down the first data channel of every open connection the filter accepts. See `multicast_reliable`.
+    pub async fn multicast_unreliable(&self, filter: impl FnMut(Identifier, &ConnectionInfo) -> bool, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        let ids = self.registry.open_matching(filter);
+        self.send_to_actor(ActorMessage::Multicast(Targets::Only(ids), Route::DataChannel(0), bytes.into())).await
+    }
+
+    /// Add a connection to a room, creating the room if needed. The connection leaves all of its rooms when it closes.
+    pub fn join_room(&self, id: Identifier, room: &str) -> Result<(), ServerError> {
+        self.registry.join_room(id, room)
+    }
+
+    /// Remove a connection from a room, succeeding even if it wasn't in the room.
+    pub fn leave_room(&self, id: Identifier, room: &str) -> Result<(), ServerError> {
+        self.registry.leave_room(id, room)
+    }
+
+    /// Connections in a room, in no particular order. Empty if the room doesn't exist.
+    pub fn room_members(&self, room: &str) -> Vec<Identifier> {
+        self.registry.room_members(room)
+    }
+
+    /// Rooms a connection is in, in no particular order.
+    pub fn rooms_of(&self, id: Identifier) -> Result<Vec<String>, ServerError> {
+        self.registry.rooms_of(id)
+    }
+
+    /// Broadcast a message reliably down the open connections in a room.
+    pub async fn broadcast_room_reliable(&self, room: &str, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::Room(room.to_string(), None), Route::WebSocket, bytes.into())).await
+    }
+
+    /// Broadcast a message down the first data channel of the open connections in a room, as with `send_unreliable`.
+    pub async fn broadcast_room_unreliable(&self, room: &str, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::Room(room.to_string(), None), Route::DataChannel(0), bytes.into())).await
+    }
+
+    /// Broadcast a message reliably down the open connections in a room, apart from the given one.
+    pub async fn broadcast_except(&self, room: &str, except: Identifier, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Multicast(Targets::Room(room.to_string(), Some(except)), Route::WebSocket, bytes.into())).await
+    }
+
+    /// Gracefully stop the server, returning once its task has finished. See `Server::drain`.
+    pub async fn drain(&self) -> Result<(), ServerError> {
+        self.stop(None).await
+    }
+
+    /// Same as drain(), but gives up waiting on connections to close after the timeout. See `Server::shutdown`.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<(), ServerError> {
+        self.stop(Some(Instant::now() + timeout)).await
+    }
+
+    async fn stop(&self, deadline: Option<Instant>) -> Result<(), ServerError> {
+        self.send_to_actor(ActorMessage::Shutdown(deadline)).await?;
+
+        // Only one caller gets to await the task
+        let task = self.take_task();
+        if let Some(task) = task && task.await.is_err() {
+            warn!("Server task panicked whilst shutting down");
+        }
+
+        Ok(())
+    }
+
+    /// Queue a message for the actor, waiting for space if the queue is full.
+    async fn send_to_actor(&self, message: ActorMessage) -> Result<(), ServerError> {
+        self.sender.send(message).await.map_err(|_| ServerError::ServerStopped)
+    }
+
+    /// Queue a message for the actor without waiting.
+    fn try_send_to_actor(&self, message: ActorMessage) -> Result<(), ServerError> {
+        self.sender.try_send(message).map_err(|err| match err {
+            TrySendError::Full(_) => ServerError::QueueFull,
+            TrySendError::Closed(_) => ServerError::ServerStopped,
+        })
+    }
+
+    /// Succeeds if the connection is open (or holds messages until it is), and has room for a reliable message when full queues should be reported as errors.
+    fn check_room(&self, id: Identifier, route: Route) -> Result<(), ServerError> {
+        let held = match self.registry.check_open(id) {
+            Ok(()) => false,
+            Err(ServerError::NotYetOpen(_)) if route.held_until_open(&self.config) => true,
+            Err(err) => return Err(err),
+        };
+        if self.config.reliable_overflow != ReliableOverflow::Error {
+            return Ok(());
+        }
+
+        // Unreliable messages make room for themselves instead, and data channels use the websocket whilst falling back
+        let falling_back = self.registry.transport_mode(id) == Some(TransportMode::WebSocketOnly);
+        let full = self.registry.queues(id).is_some_and(|queues| match route {
+            Route::DataChannel(channel) if self.config.data_channels[channel].reliability != Reliability::Reliable => false,
+            _ if held => queues.held_full(),
+            Route::WebSocket => queues.websocket_full(),
+            Route::DataChannel(_) if falling_back => queues.websocket_full(),
+            Route::DataChannel(channel) => queues.data_channel_full(channel),
+        });
+
+        match full {
+            true => Err(ServerError::Congested(id)),
+            false => Ok(()),
+        }
+    }
+
+    fn channel_index(&self, label: &str) -> Result<usize, ServerError> {
+        self.config.data_channel_index(label).ok_or_else(|| ServerError::UnknownChannel(label.to_string()))
+    }
+
+    fn take_task(&self) -> Option<task::JoinHandle<()>> {
+        self.task.lock().expect("Lock should not be poisoned").take()
+    }
+}
+
+/// Handle to a websocket + webrtc server, used for sending messages and killing active connections.
+/// 
+/// Runs an `AsyncServer` on its own OS thread, and never blocks when sending.
+/// 
+/// Payloads are anything which converts into `Bytes`, such as a `Vec<u8>` or a static slice, without being copied.
+/// Sends fail with `ServerError::Congested` when the connection's outbound queue is full, if configured with `ReliableOverflow::Error`.
+/// 
+/// Can be freely cloned, will point to the same instance.
+#[derive(Clone)]
+pub struct Server {
+    inner: AsyncServer,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Server {
+    /// Create new server listening on `listen_addr` (both TCP and UDP) with the default configuration, which will be spawned on a new OS thread.
+    /// 
+    /// See `ServerBuilder` for more options.
+    pub fn new(listen_addr: &str) -> Result<(Self, EventQueue), ServerError> {
+        ServerBuilder::new().bind(listen_addr).build()
+    }
+
+    /// Create new server from a configuration, which will be spawned on a new OS thread.
+    /// 
+    /// Fails if the runtime can't be created or either address can't be bound. Can be called from within another runtime, as nothing blocks on it.
+    pub fn with_config(config: ServerConfig) -> Result<(Self, EventQueue), ServerError> {
+        // Create an async runtime
+        let mut builder = match config.runtime {
+            RuntimeFlavour::CurrentThread => Builder::new_current_thread(),
+            RuntimeFlavour::MultiThread { worker_threads } => {
+                let mut builder = Builder::new_multi_thread();
+                if let Some(worker_threads) = worker_threads {
+                    builder.worker_threads(worker_threads);
+                }
+                builder
+            },
+        };
+
+        // Create an OS thread, start the server on our runtime there and then drive it until the server's task finishes.
+        // Nothing blocks on the caller's thread, which may be within another runtime
+        let (started, result) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let rt = match builder.enable_all().build() {
+                Ok(rt) => rt,
+                Err(err) => {
+                    let _ = started.send(Err(ServerError::Runtime(err)));
+                    return;
+                },
+            };
+            let task = match rt.block_on(AsyncServer::new(config)) {
+                Ok((inner, queue)) => {
+                    let task = inner.take_task();
+                    let _ = started.send(Ok((inner, queue)));
+                    task
+                },
+                Err(err) => {
+                    let _ = started.send(Err(err));
+                    return;
+                },
+            };
+            if let Some(task) = task && rt.block_on(task).is_err() {
+                warn!("Server task panicked");
+            }
+        });
+
+        // Only disconnected without a result if the thread panicked whilst starting
+        let (inner, queue) = result.recv().map_err(|_| ServerError::ServerStopped)??;
+
+        let server = Server {
+            inner,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        };
+
+        Ok((server, queue))
+    }
+
+    /// Address the websocket listener is bound to, useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+
+    /// Details of an open connection, the same as those reported with its Event::Open.
+    pub fn connection_info(&self, id: Identifier) -> Result<ConnectionInfo, ServerError> {
+        self.inner.connection_info(id)
+    }
+
+    /// The stage a connection has reached, from being accepted to opening, or closing whilst the server drains.
+    ///
+    /// Connections are forgotten once they close or fail to connect, after which this returns `ServerError::UnknownConnection`.
+    pub fn state(&self, id: Identifier) -> Result<ConnectionState, ServerError> {
+        self.inner.state(id)
+    }
+
+    /// Round-trip times measured for a connection, which may still be connecting.
+    ///
+    /// The websocket is timed by heartbeat pings (see `ServerConfig::ping_interval`), and the first data channel by its own pings if `ServerConfig::data_channel_ping` is set.
+    /// Pings are timed from when they are written, so messages queued ahead of them don't count.
+    /// Each is `None` until its first pong arrives, and then smoothed as in RFC 6298.
+    pub fn rtt(&self, id: Identifier) -> Result<Rtt, ServerError> {
+        self.inner.rtt(id)
+    }
+
+    /// Signal to kill a connection with a given identifier, which may still be connecting.
+    /// 
+    /// The client is sent a websocket close frame with code 1000 (normal closure) after any messages already queued for it, and its peer connection is closed.
+    /// The event queue receives an Event::Closed(id, CloseReason::Killed) to confirm the action if the connection was open, or an Event::ConnectFailed(id, ConnectFailure::Closed(CloseReason::Killed)) if it wasn't, and no further events for it.
+    pub fn kill(&mut self, id: Identifier) -> Result<(), ServerError> {
+        self.kill_with(id, CloseCode::Normal.into(), "")
+    }
+
+    /// Same as kill(), but the client's close frame has this code and reason, e.g. 4000 and "Kicked for spamming".
+    /// 
+    /// Fails with `InvalidCloseCode` for codes a server can't send. Reasons are cut short at 123 bytes, the most a close frame can hold.
+    pub fn kill_with(&mut self, id: Identifier, code: u16, reason: &str) -> Result<(), ServerError> {
+        let frame = close_frame(code, reason)?;
+        self.inner.registry.check_known(id)?;
+        self.inner.try_send_to_actor(ActorMessage::Kill(id, frame))
     }
 
     /// Send a message down a connection with the given identifier. Uses websockets as a reliable communication protocol.
-    pub fn send_reliable(&mut self, id: Identifier, bytes: Vec<u8>) {
-        self.sender.blocking_send(ActorMessage::SendReliable(id, bytes)).expect("Actor should be alive");
+    pub fn send_reliable(&mut self, id: Identifier, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.check_room(id, Route::WebSocket)?;
+        self.inner.try_send_to_actor(ActorMessage::SendReliable(id, bytes.into()))
+    }
+
+    /// Send a message down a connection with the given identifier. Uses the first configured webrtc datachannel, which is unreliable by default.
+    pub fn send_unreliable(&mut self, id: Identifier, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.check_room(id, Route::DataChannel(0))?;
+        self.inner.try_send_to_actor(ActorMessage::SendDataChannel(id, 0, bytes.into()))
+    }
+
+    /// Send a message down the webrtc datachannel with the given label, as configured in `ServerConfig::data_channels`.
+    pub fn send(&mut self, id: Identifier, channel: &str, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        let channel = self.inner.channel_index(channel)?;
+        self.inner.check_room(id, Route::DataChannel(channel))?;
+        self.inner.try_send_to_actor(ActorMessage::SendDataChannel(id, channel, bytes.into()))
+    }
+
+    /// Broadcast a message reliably down all open connections, and those holding messages until they open (see `PreOpenBuffer`).
+    pub fn broadcast(&mut self, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::All, Route::WebSocket, bytes.into()))
+    }
+
+    /// Broadcast a message down the first data channel of all open connections, as with `send_unreliable`. Also held by connections which haven't opened if `PreOpenBuffer::All` is set.
+    pub fn broadcast_unreliable(&mut self, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::All, Route::DataChannel(0), bytes.into()))
+    }
+
+    /// Send a message reliably down each of the given connections, skipping any which aren't open (unless held, see `ServerConfig::pre_open_buffer`).
+    pub fn send_many(&mut self, ids: &[Identifier], bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Only(ids.to_vec()), Route::WebSocket, bytes.into()))
+    }
+
+    /// Send a message down the first data channel of each of the given connections, skipping any which aren't open (unless held, see `ServerConfig::pre_open_buffer`).
+    pub fn send_many_unreliable(&mut self, ids: &[Identifier], bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Only(ids.to_vec()), Route::DataChannel(0), bytes.into()))
+    }
+
+    /// Send a message reliably down every open connection the filter accepts.
+    /// 
+    /// The filter is called whilst holding the server's view of connections, so must not call back into the server.
+    pub fn multicast_reliable(&mut self, filter: impl FnMut(Identifier, &ConnectionInfo) -> bool, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        let ids = self.inner.registry.open_matching(filter);
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Only(ids), Route::WebSocket, bytes.into()))
+    }
+
+    /// Send a message down the first data channel of every open connection the filter accepts. See `multicast_reliable`.
+    pub fn multicast_unreliable(&mut self, filter: impl FnMut(Identifier, &ConnectionInfo) -> bool, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        let ids = self.inner.registry.open_matching(filter);
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Only(ids), Route::DataChannel(0), bytes.into()))
+    }
+
+    /// Add a connection to a room, creating the room if needed. The connection leaves all of its rooms when it closes.
+    pub fn join_room(&mut self, id: Identifier, room: &str) -> Result<(), ServerError> {
+        self.inner.join_room(id, room)
+    }
+
+    /// Remove a connection from a room, succeeding even if it wasn't in the room.
+    pub fn leave_room(&mut self, id: Identifier, room: &str) -> Result<(), ServerError> {
+        self.inner.leave_room(id, room)
+    }
+
+    /// Connections in a room, in no particular order. Empty if the room doesn't exist.
+    pub fn room_members(&self, room: &str) -> Vec<Identifier> {
+        self.inner.room_members(room)
+    }
+
+    /// Rooms a connection is in, in no particular order.
+    pub fn rooms_of(&self, id: Identifier) -> Result<Vec<String>, ServerError> {
+        self.inner.rooms_of(id)
+    }
+
+    /// Broadcast a message reliably down the open connections in a room.
+    pub fn broadcast_room_reliable(&mut self, room: &str, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Room(room.to_string(), None), Route::WebSocket, bytes.into()))
     }
 
-    /// Send a message down a connection with the given identifier. Uses a webrtc datachannel over UDP as an unreliable communication protocol.
-    pub fn send_unreliable(&mut self, id: Identifier, bytes: Vec<u8>) {
-        self.sender.blocking_send(ActorMessage::SendUnreliable(id, bytes)).expect("Actor should be alive");
+    /// Broadcast a message down the first data channel of the open connections in a room, as with `send_unreliable`.
+    pub fn broadcast_room_unreliable(&mut self, room: &str, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Room(room.to_string(), None), Route::DataChannel(0), bytes.into()))
     }
 
-    /// Broadcast a message reliably down all active connections.
-    pub fn broadcast(&mut self, bytes: Vec<u8>) {
-        self.sender.blocking_send(ActorMessage::Broadcast(bytes)).expect("Actor should be alive");
+    /// Broadcast a message reliably down the open connections in a room, apart from the given one.
+    pub fn broadcast_except(&mut self, room: &str, except: Identifier, bytes: impl Into<Bytes>) -> Result<(), ServerError> {
+        self.inner.try_send_to_actor(ActorMessage::Multicast(Targets::Room(room.to_string(), Some(except)), Route::WebSocket, bytes.into()))
     }
 
+    /// Gracefully stop the server, blocking until its thread has exited.
+    /// 
+    /// New TCP streams are no longer accepted, every connection is sent a websocket close frame and has its data channel closed,
+    /// and an Event::Closed is pushed for every open connection (or an Event::ConnectFailed for those still connecting). Messages already queued are sent before the close frame,
+    /// unless a client takes nothing for `pong_timeout`. Any clone of the server can be used, later calls will fail with ServerStopped.
+    /// Must not be called from within an async context, as it blocks.
+    pub fn drain(&mut self) -> Result<(), ServerError> {
+        self.stop(None)
+    }
+
+    /// Same as drain(), but gives up waiting on connections to close after the timeout, pushing an Event::Closed or Event::ConnectFailed for any that remain.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<(), ServerError> {
+        self.stop(Some(Instant::now() + timeout))
+    }
+
+    fn stop(&mut self, deadline: Option<Instant>) -> Result<(), ServerError> {
+        self.inner.sender
+            .blocking_send(ActorMessage::Shutdown(deadline))
+            .map_err(|_| ServerError::ServerStopped)?;
+
+        // Only one caller gets to join the thread
+        let thread = self.thread.lock().expect("Lock should not be poisoned").take();
+        if let Some(thread) = thread && thread.join().is_err() {
+            warn!("Server thread panicked whilst shutting down");
+        }
+
+        Ok(())
+    }
 }
 
 
 struct Actor {
     // Hold ownership of handles to connection actors.
     connections: HashMap<Identifier, connection_state::Connection>,
+    // Identifier to give to the next connection, only ever increases so identifiers aren't reused.
+    next_identifier: Identifier,
     // Hold reference to the message queue, on which we can push incoming messages.
     queue: EventQueue,
+    // Shared view of connections, kept in sync with 'connections' for Server handles to read.
+    registry: Registry,
     // Hold a sender to clone and pass to new connection actors, so they can emit events to us.
     connection_emit: mpsc::Sender<(Identifier, ConnectionEvent)>,
     // Handle to WebRTC API that is passed to new connections
-    api: RtcApiHandle
+    api: RtcApiHandle,
+    // Configuration shared with new connections
+    config: Arc<ServerConfig>,
+    // Set once shutting down, no new connections are accepted
+    draining: bool,
+    // When shutting down, the time after which remaining connections are abandoned
+    drain_deadline: Option<Instant>,
 }
 
 impl Actor {
-    pub fn new(connection_emit: mpsc::Sender<(Identifier, ConnectionEvent)>, queue: EventQueue, api: RtcApiHandle) -> Self {
+    pub fn new(connection_emit: mpsc::Sender<(Identifier, ConnectionEvent)>, queue: EventQueue, registry: Registry, api: RtcApiHandle, config: Arc<ServerConfig>) -> Self {
         Self {
             connections: HashMap::new(),
+            next_identifier: Identifier::default(),
             queue,
+            registry,
             connection_emit,
             api,
+            config,
+            draining: false,
+            drain_deadline: None,
         }
     }
 
     pub fn handle_message(&mut self, message: ActorMessage) {
         match message {
-            ActorMessage::Kill(id) => {
+            ActorMessage::Kill(id, frame) => {
                 info!("Received kill instruction for connection={}", id);
-                self.connections.remove(&id);
+                // Report it straight away, any events the connection emits whilst closing are ignored once it is untracked
+                let Some(mut conn) = self.connections.remove(&id) else { return };
+                self.registry.remove(id);
+                match conn.is_alive() {
+                    true => self.queue.push(Event::Closed(id, CloseReason::Killed)),
+                    false => self.queue.push(Event::ConnectFailed(id, ConnectFailure::Closed(CloseReason::Killed))),
+                }
+
+                // Dropping the handle afterwards still lets the connection send the close frame and tear down its peer connection
+                conn.close(frame, CloseReason::Killed);
             },
             ActorMessage::HandleConnectionEvent(id, connection_event) => {
                 info!("Event registered: {:?}", connection_event);
+
+                // Events may still arrive from a connection that has just been killed
+                let Some(conn) = self.connections.get_mut(&id) else {
+                    warn!("Ignoring event from untracked connection={}", id);
+                    return;
+                };
+
                 match connection_event {
-                    ConnectionEvent::ConnectionEstablished => {
+                    ConnectionEvent::ConnectionEstablished(info) => {
                         // Set to ready
-                        self.connections.get_mut(&id).expect("Connection should be stored here").set_alive();
-                        self.queue.push(Event::Open(id));
+                        conn.set_alive();
+                        let changed = self.registry.set_open(id, (*info).clone());
+                        self.queue.push(Event::Open(id, *info));
+                        if changed && self.config.state_events {
+                            self.queue.push(Event::StateChanged(id, ConnectionState::Open));
+                        }
                     },
-                    ConnectionEvent::ConnectionTerminated => {
-                        // Kill connection actor by dropping its handle
-                        self.connections.remove(&id).expect("Connection should be stored here");
-                        self.queue.push(Event::Closed(id));
+                    ConnectionEvent::StateChanged(state) => {
+                        self.set_state(id, state);
+                    },
+                    ConnectionEvent::RoundTrip(rtt) => {
+                        self.registry.set_rtt(id, rtt);
+                    },
+                    ConnectionEvent::UpgradedToWebRtc(candidate_pair) => {
+                        self.registry.update_info(id, |info| {
+                            info.transport_mode = TransportMode::WebRtc;
+                            info.candidate_pair = candidate_pair;
+                        });
+                        self.queue.push(Event::ModeChanged(id, TransportMode::WebRtc));
+                    },
+                    ConnectionEvent::Backpressure(backpressure) => {
+                        self.queue.push(Event::Backpressure(id, backpressure));
+                    },
+                    ConnectionEvent::ConnectionTerminated(reason) => {
+                        // Kill connection actor by dropping its handle, connections which never opened failed instead
+                        let was_alive = conn.is_alive();
+                        self.connections.remove(&id);
+                        self.registry.remove(id);
+                        match was_alive {
+                            true => self.queue.push(Event::Closed(id, reason)),
+                            false => self.queue.push(Event::ConnectFailed(id, ConnectFailure::Closed(reason))),
+                        }
+                    },
+                    ConnectionEvent::ConnectionFailed(failure) => {
+                        // Never opened, so reported separately from connections which closed
+                        self.connections.remove(&id);
+                        self.registry.remove(id);
+                        self.queue.push(Event::ConnectFailed(id, failure));
                     },
                     ConnectionEvent::MessageReceived(message) => {
                         // Push to queue
@@ -172,44 +721,115 @@ impl Actor {
                     }
                 }
             },
-            ActorMessage::HandleNewStream(tcp_stream) => {
+            ActorMessage::HandleNewStream(tcp_stream, remote_addr) => {
                 // Assign new identifier
-                let id = self.next_free_identifier();
+                let id = self.next_identifier;
+                self.next_identifier = id.next();
 
                 // Spawn actor
-                let handle = ConnectionHandle::new(id, self.connection_emit.clone(), tcp_stream, self.api.clone());
+                let handle = ConnectionHandle::new(id, self.connection_emit.clone(), tcp_stream, remote_addr, self.api.clone(), Arc::clone(&self.config));
 
                 // Store ownership of handle whilst it initialises
+                self.registry.insert(id, handle.queues());
                 self.connections.insert(id, connection_state::Connection::new(handle));
+                if self.config.state_events {
+                    self.queue.push(Event::StateChanged(id, ConnectionState::Accepted));
+                }
             },
             ActorMessage::SendReliable(to, bytes) => {
-                let conn = self.connections.get_mut(&to).expect("Connection with id should be available.");
-
-                conn.get_handle().send_reliable(bytes);
+                match self.handle_for(to, Route::WebSocket) {
+                    Some(handle) => handle.send_reliable(bytes),
+                    None => warn!("Dropping reliable message for connection={}, which is not open", to),
+                }
+            },
+            ActorMessage::SendDataChannel(to, channel, bytes) => {
+                match self.handle_for(to, Route::DataChannel(channel)) {
+                    Some(handle) => handle.send_data_channel(channel, bytes),
+                    None => warn!("Dropping data channel message for connection={}, which is not open", to),
+                }
             },
-            ActorMessage::SendUnreliable(to, bytes) => {
-                let conn = self.connections.get_mut(&to).expect("Connection with id should be available.");
+            ActorMessage::Multicast(targets, route, bytes) => {
+                // Cloning only bumps the reference count, and connections which aren't open yet are skipped unless they hold messages
+                let ids = match targets {
+                    Targets::All => self.connections.keys().copied().collect(),
+                    Targets::Room(room, except) => {
+                        let mut members = self.registry.room_members(&room);
+                        members.retain(|id| Some(*id) != except);
+                        members
+                    },
+                    Targets::Only(ids) => ids,
+                };
 
-                conn.get_handle().send_unreliable(bytes);
+                for id in ids {
+                    if let Some(handle) = self.handle_for(id, route) {
+                        route.send(handle, bytes.clone());
+                    }
+                }
             },
-            ActorMessage::Broadcast(bytes) => {
-                self.connections
-                    .values_mut()
-                    .filter(|conn| conn.is_alive())
-                    .for_each(|conn| conn.get_handle().send_reliable(bytes.clone()));
+            ActorMessage::Shutdown(deadline) => {
+                info!("Received shutdown instruction");
+
+                // Keep the earliest deadline if asked more than once
+                self.drain_deadline = match (self.drain_deadline, deadline) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+
+                if !self.draining {
+                    self.draining = true;
+                    let frame = CloseFrame { code: CloseCode::Away, reason: "Server shutting down".into() };
+                    self.connections
+                        .values_mut()
+                        .for_each(|conn| conn.close(frame.clone(), CloseReason::ServerShutdown));
+                    let ids: Vec<_> = self.connections.keys().copied().collect();
+                    for id in ids {
+                        self.set_state(id, ConnectionState::Closing);
+                    }
+                }
             },
         }
     }
 
-    /// Provides an unused identifier for fresh connections to use, assumes Identifier type won't overflow if incremented by one.
-    fn next_free_identifier(&self) -> Identifier {
-        self.connections.keys().max().map(|i| i + 1).unwrap_or(0)
+    /// Handle to send on, if the connection is open or will hold messages on this route until it is.
+    fn handle_for(&mut self, id: Identifier, route: Route) -> Option<&mut ConnectionHandle> {
+        let conn = self.connections.get_mut(&id)?;
+        match route.held_until_open(&self.config) {
+            true => Some(conn.get_handle_before_open()),
+            false => conn.get_handle(),
+        }
     }
+
+    /// Move a connection on to another stage, reporting it if asked to.
+    fn set_state(&mut self, id: Identifier, state: ConnectionState) {
+        if self.registry.set_state(id, state) && self.config.state_events {
+            self.queue.push(Event::StateChanged(id, state));
+        }
+    }
+
+    /// True once shutting down and every connection has closed.
+    fn is_drained(&self) -> bool {
+        self.draining && self.connections.is_empty()
+    }
+
+    /// Stop waiting on connections to close, pushing an Event::Closed for those which were open and an Event::ConnectFailed for the rest.
+    fn abandon_connections(&mut self) {
+        for (id, conn) in self.connections.drain() {
+            self.registry.remove(id);
+            match conn.is_alive() {
+                true => self.queue.push(Event::Closed(id, CloseReason::ServerShutdown)),
+                false => self.queue.push(Event::ConnectFailed(id, ConnectFailure::Closed(CloseReason::ServerShutdown))),
+            }
+        }
+    }
+
 }
 
 
-/// Maintains a 'liveness' invariant on a ConnectionHandle, panicking if it has not been set to 'alive'.
+/// Maintains a 'liveness' invariant on a ConnectionHandle, only exposing it once it has been set to 'alive'.
 mod connection_state {
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    use crate::event::CloseReason;
     use super::connection::ConnectionHandle;
 
     pub struct Connection {
@@ -222,12 +842,717 @@ mod connection_state {
         pub fn set_alive(&mut self) { self.alive = true; }
         pub fn is_alive(&self) -> bool { self.alive }
 
-        pub fn get_handle(&mut self) -> &mut ConnectionHandle {
+        /// Connections can be closed whether or not they are alive.
+        pub fn close(&mut self, frame: CloseFrame, reason: CloseReason) { self.handle.close(frame, reason); }
+
+        /// For messages which the connection holds until it opens.
+        pub fn get_handle_before_open(&mut self) -> &mut ConnectionHandle { &mut self.handle }
+
+        pub fn get_handle(&mut self) -> Option<&mut ConnectionHandle> {
             if self.alive {
-                &mut self.handle
+                Some(&mut self.handle)
             } else {
-                panic!("Connection not tracked as live yet.")
+                None
+            }
+        }
+    }
+}
+
+/// Close frame to send when killing a connection, with the reason cut short to fit.
+fn close_frame(code: u16, reason: &str) -> Result<CloseFrame, ServerError> {
+    let code = CloseCode::from(code);
+    if !code.is_allowed() {
+        return Err(ServerError::InvalidCloseCode(code.into()));
+    }
+
+    // Control frames hold 125 bytes, two of which are the code
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    Ok(CloseFrame { code, reason: reason[..end].into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use futures_util::StreamExt;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame}, Error, Message}, MaybeTlsStream, WebSocketStream};
+
+    use crate::{auth::AuthDecision, config::{DataChannelConfig, PreOpenBuffer, ReliableOverflow, ServerBuilder, UnreliableOverflow}, error::ServerError, event::{CloseReason, ConnectFailure, ConnectionState, Event, Identifier, TransportMode}, info::ConnectionInfo};
+
+    #[test]
+    fn bind_failure_is_returned() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+
+        let result = ServerBuilder::new().tcp_addr(&addr).udp_addr("127.0.0.1:0").build();
+
+        assert!(matches!(result, Err(ServerError::Bind(_))));
+    }
+
+    #[test]
+    fn unknown_connection_is_an_error() {
+        let (mut server, _queue) = ServerBuilder::new().bind("127.0.0.1:0").build().unwrap();
+        let id = Identifier::from(7);
+
+        assert!(matches!(server.send_reliable(id, vec![1]), Err(ServerError::UnknownConnection(i)) if i == id));
+        assert!(matches!(server.send_unreliable(id, vec![1]), Err(ServerError::UnknownConnection(i)) if i == id));
+        assert!(matches!(server.kill(id), Err(ServerError::UnknownConnection(i)) if i == id));
+        assert!(server.broadcast(vec![1]).is_ok());
+
+        assert!(matches!(server.send(id, "game", vec![1]), Err(ServerError::UnknownConnection(i)) if i == id));
+        assert!(matches!(server.send(id, "missing", vec![1]), Err(ServerError::UnknownChannel(label)) if label == "missing"));
+    }
+
+    #[test]
+    fn drain_closes_connections() {
+        let (mut server, _queue) = ServerBuilder::new().bind("127.0.0.1:0").build().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let mut client = rt.block_on(connect(server.local_addr()));
+
+        server.drain().unwrap();
+
+        assert_eq!(rt.block_on(expect_close_frame(&mut client)).code, CloseCode::Away);
+    }
+
+    #[test]
+    fn drain_flushes_slow_clients() {
+        let (mut server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .build()
+            .unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let mut client = rt.block_on(connect(server.local_addr()));
+        let id = match queue.wait_timeout(Duration::from_secs(2)) {
+            Some(Event::Open(id, _)) => id,
+            other => panic!("Expected open event, got {:?}", other),
+        };
+
+        // Far more than the socket buffers hold, so most is still queued when draining
+        for i in 0..100 {
+            server.send_reliable(id, vec![i; 64 * 1024]).unwrap();
+        }
+
+        let reader = rt.spawn(async move {
+            let mut received = 0;
+            loop {
+                match client.next().await {
+                    Some(Ok(Message::Binary(bytes))) => {
+                        assert_eq!(bytes[0], received);
+                        received += 1;
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                    },
+                    Some(Ok(Message::Close(Some(frame)))) => break (received, frame.code),
+                    Some(Ok(Message::Text(_) | Message::Ping(_))) => continue,
+                    other => panic!("Expected binary message or close frame, got {:?}", other),
+                }
+            }
+        });
+
+        server.drain().unwrap();
+        assert_eq!(rt.block_on(reader).unwrap(), (100, CloseCode::Away));
+    }
+
+    #[tokio::test]
+    async fn drain_closes_connections_with_full_channels() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .connection_capacity(1)
+            .outbound_limit(256, ReliableOverflow::Error, UnreliableOverflow::DropOldest)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let Some(Event::Open(id, _)) = queue.next().await else { panic!("Expected open event") };
+
+        // Most of these don't fit in the connection's channel, which is still full when asked to close
+        for i in 0..50 {
+            let _ = server.send_reliable(id, vec![i]).await;
+        }
+        tokio::time::timeout(Duration::from_secs(5), server.drain()).await.unwrap().unwrap();
+
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => break assert_eq!(frame.code, CloseCode::Away),
+                Some(Ok(Message::Binary(_) | Message::Text(_) | Message::Ping(_))) => continue,
+                other => panic!("Expected close frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn server_starts_within_runtime() {
+        let (mut server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .build()
+            .unwrap();
+
+        // Serves clients from its own thread, without blocking this runtime
+        let mut client = connect(server.local_addr()).await;
+        let Some(Event::Open(id, _)) = queue.next().await else { panic!("Expected open event") };
+        server.kill(id).unwrap();
+
+        assert_eq!(expect_close_frame(&mut client).await.code, CloseCode::Normal);
+    }
+
+    #[tokio::test]
+    async fn async_server_runs_on_current_runtime() {
+        let (server, mut queue) = ServerBuilder::new().bind("127.0.0.1:0").build_async().await.unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+
+        // First connection gets the first identifier, but is not open
+        let id = Identifier::from(0);
+        assert!(matches!(server.send_reliable(id, vec![1]).await, Err(ServerError::NotYetOpen(i)) if i == id));
+
+        let id = Identifier::from(1);
+        assert!(matches!(server.send_reliable(id, vec![1]).await, Err(ServerError::UnknownConnection(i)) if i == id));
+
+        server.drain().await.unwrap();
+
+        assert_eq!(expect_close_frame(&mut client).await.code, CloseCode::Away);
+
+        // Event stream ends once the server has stopped, after the connection that never opened fails
+        assert!(matches!(queue.next().await, Some(Event::ConnectFailed(i, ConnectFailure::Closed(CloseReason::ServerShutdown))) if i == Identifier::from(0)));
+        assert!(queue.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn silent_client_times_out() {
+        let (server, _queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .heartbeat(Duration::from_millis(50), Duration::from_millis(200))
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+
+        // Not reading means pings go unanswered
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Server should have dropped the connection, skipping anything sent before then
+        let dropped = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(Message::Text(_) | Message::Ping(_))) => continue,
+                    other => break other,
+                }
+            }
+        }).await.unwrap();
+        assert!(matches!(dropped, None | Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn authenticator_rejects_clients() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .authenticator(|request: &ConnectionInfo| match request.query_param("token").as_deref() {
+                Some("secret") => AuthDecision::Accept(None),
+                _ => AuthDecision::Reject(401, String::from("Bad token")),
+            })
+            .build_async()
+            .await
+            .unwrap();
+
+        let rejected = tokio_tungstenite::connect_async(format!("ws://{}/?token=wrong", server.local_addr())).await;
+        match rejected {
+            Err(Error::Http(response)) => {
+                assert_eq!(response.status(), 401);
+                assert_eq!(response.body().as_deref(), Some("Bad token".as_bytes()));
+            },
+            other => panic!("Expected rejection, got {:?}", other),
+        }
+
+        // Rejected connection is forgotten without ever opening
+        let failed = tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap();
+        let id = Identifier::from(0);
+        assert!(matches!(failed, Some(Event::ConnectFailed(i, ConnectFailure::Rejected(401))) if i == id));
+        assert!(matches!(server.kill(id).await, Err(ServerError::UnknownConnection(i)) if i == id));
+
+        let (mut accepted, _) = tokio_tungstenite::connect_async(format!("ws://{}/?token=secret", server.local_addr())).await.unwrap();
+        assert!(matches!(accepted.next().await, Some(Ok(Message::Text(_)))));
+    }
+
+    #[tokio::test]
+    async fn connect_timeouts_fail_connections() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .connect_timeouts(Duration::from_millis(100), Duration::from_millis(200))
+            .build_async()
+            .await
+            .unwrap();
+
+        // Never sends the upgrade request
+        let _silent = TcpStream::connect(server.local_addr()).await.unwrap();
+        let failed = tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap();
+        assert!(matches!(failed, Some(Event::ConnectFailed(_, ConnectFailure::HandshakeTimeout))));
+
+        // Never answers the SDP offer, so is told why it was dropped
+        let mut client = connect(server.local_addr()).await;
+        let failed = tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap();
+        let id = match failed {
+            Some(Event::ConnectFailed(id, ConnectFailure::WebRtcTimeout)) => id,
+            other => panic!("Expected failure event, got {:?}", other),
+        };
+        let frame = expect_close_frame(&mut client).await;
+        assert_eq!(frame.code, CloseCode::Error);
+        assert!(matches!(server.send_reliable(id, vec![1]).await, Err(ServerError::UnknownConnection(_))));
+    }
+
+    #[tokio::test]
+    async fn kill_fails_connecting_clients() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .build_async()
+            .await
+            .unwrap();
+
+        // Never sends the upgrade request, so is still connecting when killed
+        let _silent = TcpStream::connect(server.local_addr()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = Identifier::from(0);
+        server.kill(id).await.unwrap();
+
+        let failed = tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap();
+        assert!(matches!(failed, Some(Event::ConnectFailed(i, ConnectFailure::Closed(CloseReason::Killed))) if i == id));
+    }
+
+    #[tokio::test]
+    async fn reports_connection_state() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .state_events()
+            .build_async()
+            .await
+            .unwrap();
+
+        // Client never answers the SDP offer, so the connection gets no further
+        let _client = connect(server.local_addr()).await;
+
+        let mut states = Vec::new();
+        while states.len() < 3 {
+            match tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap() {
+                Some(Event::StateChanged(id, state)) => states.push((id, state)),
+                other => panic!("Expected state change, got {:?}", other),
+            }
+        }
+        let id = Identifier::from(0);
+        assert_eq!(states, [(id, ConnectionState::Accepted), (id, ConnectionState::Upgraded), (id, ConnectionState::OfferSent)]);
+        assert_eq!(server.state(id).unwrap(), ConnectionState::OfferSent);
+        assert!(matches!(server.state(Identifier::from(1)), Err(ServerError::UnknownConnection(_))));
+    }
+
+    #[tokio::test]
+    async fn drain_fails_connecting_clients() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .state_events()
+            .build_async()
+            .await
+            .unwrap();
+
+        // Never sends the upgrade request, so is still connecting when the server drains
+        let _silent = TcpStream::connect(server.local_addr()).await.unwrap();
+        let id = Identifier::from(0);
+        let accepted = tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap();
+        assert!(matches!(accepted, Some(Event::StateChanged(i, ConnectionState::Accepted)) if i == id));
+
+        server.drain().await.unwrap();
+
+        // Apps tracking connections from Accepted are always told when they go away
+        assert!(matches!(queue.next().await, Some(Event::StateChanged(i, ConnectionState::Closing)) if i == id));
+        assert!(matches!(queue.next().await, Some(Event::ConnectFailed(i, ConnectFailure::Closed(CloseReason::ServerShutdown))) if i == id));
+    }
+
+    #[tokio::test]
+    async fn measures_websocket_rtt() {
+        let (server, _queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .heartbeat(Duration::from_millis(50), Duration::from_secs(2))
+            .build_async()
+            .await
+            .unwrap();
+
+        // Reading lets the client answer pings
+        let mut client = connect(server.local_addr()).await;
+        tokio::spawn(async move { while client.next().await.is_some() {} });
+
+        let id = Identifier::from(0);
+        let rtt = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match server.rtt(id).unwrap().websocket {
+                    Some(estimate) => break estimate,
+                    None => tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            }
+        }).await.unwrap();
+        assert!(rtt.smoothed < Duration::from_secs(1));
+        assert_eq!(server.rtt(id).unwrap().data_channel, None);
+    }
+
+    #[tokio::test]
+    async fn websocket_rtt_excludes_queued_messages() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .heartbeat(Duration::from_millis(100), Duration::from_secs(5))
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let Some(Event::Open(id, _)) = queue.next().await else { panic!("Expected open event") };
+
+        // Far more than the socket buffers hold, so pings wait behind most of it whilst the client isn't reading
+        for _ in 0..20 {
+            server.send_reliable(id, vec![0; 1024 * 1024]).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for _ in 0..20 {
+            next_binary(&mut client).await;
+        }
+
+        // Keep reading so the client answers the pings that waited
+        tokio::spawn(async move { while client.next().await.is_some() {} });
+        let rtt = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match server.rtt(id).unwrap().websocket {
+                    Some(estimate) => break estimate,
+                    None => tokio::time::sleep(Duration::from_millis(5)).await,
+                }
+            }
+        }).await.unwrap();
+        assert!(rtt.smoothed < Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn disconnects_when_connection_channel_overflows() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .connection_capacity(1)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let id = match queue.next().await {
+            Some(Event::Open(id, _)) => id,
+            other => panic!("Expected open event, got {:?}", other),
+        };
+
+        // Sent faster than the connection can take them from its channel
+        for i in 0..10 {
+            server.send_reliable(id, vec![i]).await.unwrap();
+        }
+
+        let frame = loop {
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => break frame,
+                Some(Ok(_)) => continue,
+                other => panic!("Expected close frame, got {:?}", other),
+            }
+        };
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert!(matches!(queue.next().await, Some(Event::Closed(i, CloseReason::SlowConsumer)) if i == id));
+    }
+
+    #[tokio::test]
+    async fn reliable_channels_stay_reliable_over_websocket() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .data_channels([DataChannelConfig::reliable_unordered("chat")])
+            .outbound_limit(4, ReliableOverflow::Error, UnreliableOverflow::DropOldest)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        assert!(matches!(queue.next().await, Some(Event::Open(_, _))));
+        let id = Identifier::default();
+
+        // Client isn't reading, so the websocket queue fills once the socket buffers have
+        let mut sent = 0;
+        loop {
+            match server.send(id, "chat", vec![sent; 1024 * 1024]).await {
+                Ok(()) => sent += 1,
+                Err(ServerError::Congested(_)) => break,
+                Err(err) => panic!("Expected congestion, got {:?}", err),
+            }
+            assert!(sent < 100);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Nothing accepted was dropped to make room
+        for i in 0..sent {
+            assert_eq!(next_binary(&mut client).await[0], i);
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_websocket() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::from_millis(200))
+            .build_async()
+            .await
+            .unwrap();
+
+        // Client never answers the SDP offer, so WebRTC can't connect
+        let mut client = connect(server.local_addr()).await;
+
+        let id = match tokio::time::timeout(Duration::from_secs(2), queue.next()).await.unwrap() {
+            Some(Event::Open(id, info)) => {
+                assert_eq!(info.transport_mode, TransportMode::WebSocketOnly);
+                id
+            },
+            other => panic!("Expected open event, got {:?}", other),
+        };
+
+        // Unreliable messages go over the websocket instead
+        server.send_unreliable(id, vec![1, 2]).await.unwrap();
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Binary(bytes))) => {
+                    assert_eq!(bytes.as_ref(), [1, 2]);
+                    break;
+                },
+                Some(Ok(Message::Text(_) | Message::Ping(_))) => continue,
+                other => panic!("Expected binary message, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_messages_until_open() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::from_millis(200))
+            .buffer_until_open(PreOpenBuffer::Reliable)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let id = Identifier::default();
+
+        // Only reliable messages are held
+        assert!(matches!(server.send_unreliable(id, vec![0]).await, Err(ServerError::NotYetOpen(i)) if i == id));
+        server.send_reliable(id, vec![1]).await.unwrap();
+        server.send_reliable(id, vec![2]).await.unwrap();
+
+        // Broadcasts are held too, on the same routes
+        server.broadcast_unreliable(vec![3]).await.unwrap();
+        server.broadcast(vec![4]).await.unwrap();
+
+        assert!(matches!(queue.next().await, Some(Event::Open(i, _)) if i == id));
+        assert_eq!(next_binary(&mut client).await, [1]);
+        assert_eq!(next_binary(&mut client).await, [2]);
+        assert_eq!(next_binary(&mut client).await, [4]);
+    }
+
+    #[tokio::test]
+    async fn applies_reliable_overflow_to_held_messages() {
+        let (server, _queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .buffer_until_open(PreOpenBuffer::Reliable)
+            .outbound_limit(2, ReliableOverflow::Error, UnreliableOverflow::DropOldest)
+            .build_async()
+            .await
+            .unwrap();
+
+        // Client never answers the SDP offer, so messages are held
+        let _client = connect(server.local_addr()).await;
+        let id = Identifier::default();
+        server.send_reliable(id, vec![1]).await.unwrap();
+        server.send_reliable(id, vec![2]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(server.send_reliable(id, vec![3]).await, Err(ServerError::Congested(i)) if i == id));
+
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .buffer_until_open(PreOpenBuffer::Reliable)
+            .outbound_limit(2, ReliableOverflow::Disconnect, UnreliableOverflow::DropOldest)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        for i in 0..3 {
+            server.send_reliable(id, vec![i]).await.unwrap();
+        }
+        assert!(matches!(queue.next().await, Some(Event::ConnectFailed(i, ConnectFailure::Closed(CloseReason::SlowConsumer))) if i == id));
+        assert_eq!(expect_close_frame(&mut client).await.code, CloseCode::Policy);
+    }
+
+    #[tokio::test]
+    async fn kill_sends_close_frame() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let Some(Event::Open(id, _)) = queue.next().await else { panic!("Expected open event") };
+
+        assert!(matches!(server.kill_with(id, 1005, "").await, Err(ServerError::InvalidCloseCode(1005))));
+        server.kill_with(id, 4000, "Kicked").await.unwrap();
+
+        let frame = expect_close_frame(&mut client).await;
+        assert_eq!((u16::from(frame.code), frame.reason.as_str()), (4000, "Kicked"));
+        assert!(matches!(queue.next().await, Some(Event::Closed(i, CloseReason::Killed)) if i == id));
+        assert!(matches!(server.kill(id).await, Err(ServerError::UnknownConnection(i)) if i == id));
+    }
+
+    #[tokio::test]
+    async fn kill_reaches_connections_with_full_channels() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .connection_capacity(1)
+            .outbound_limit(256, ReliableOverflow::Error, UnreliableOverflow::DropOldest)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let Some(Event::Open(id, _)) = queue.next().await else { panic!("Expected open event") };
+
+        // The connection's channel is still full when the kill arrives, and its handle is dropped straight after
+        for i in 0..50 {
+            let _ = server.send_reliable(id, vec![i]).await;
+        }
+        server.kill_with(id, 4000, "Kicked").await.unwrap();
+
+        let frame = loop {
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => break frame,
+                Some(Ok(Message::Binary(_) | Message::Text(_) | Message::Ping(_))) => continue,
+                other => panic!("Expected close frame, got {:?}", other),
+            }
+        };
+        assert_eq!((u16::from(frame.code), frame.reason.as_str()), (4000, "Kicked"));
+        assert!(matches!(queue.next().await, Some(Event::Closed(i, CloseReason::Killed)) if i == id));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn serves_wss() {
+        use std::sync::Arc;
+        use tokio_rustls::{rustls::{self, pki_types::{pem::PemObject, CertificateDer, ServerName}}, TlsConnector};
+        use crate::tls::TlsConfig;
+
+        let cert = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/cert.pem");
+        let key = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/key.pem");
+        let (server, _queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .tls(TlsConfig::from_pem_files(cert, key).unwrap())
+            .build_async()
+            .await
+            .unwrap();
+
+        // Client trusts the self-signed certificate
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(cert).unwrap()).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let tcp = TcpStream::connect(server.local_addr()).await.unwrap();
+        let tls = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async("wss://localhost/", tls).await.unwrap();
+
+        assert!(matches!(client.next().await, Some(Ok(Message::Text(_)))));
+    }
+
+    #[tokio::test]
+    async fn multicasts_to_connections() {
+        let (server, mut queue) = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .webrtc_fallback(Duration::ZERO)
+            .build_async()
+            .await
+            .unwrap();
+
+        let mut client = connect(server.local_addr()).await;
+        let Some(Event::Open(id, _)) = queue.next().await else { panic!("Expected open event") };
+
+        server.join_room(id, "lobby").unwrap();
+        assert_eq!(server.room_members("lobby"), vec![id]);
+
+        // Skipped member gets nothing, so the first message to arrive is from the second broadcast
+        server.broadcast_except("lobby", id, vec![1]).await.unwrap();
+        server.broadcast_room_reliable("lobby", vec![2]).await.unwrap();
+        assert_eq!(next_binary(&mut client).await, [2]);
+
+        // Connections which aren't open are skipped
+        let missing = Identifier::from(9);
+        server.send_many(&[missing, id], vec![3]).await.unwrap();
+        assert_eq!(next_binary(&mut client).await, [3]);
+        server.multicast_reliable(|_, info| info.path == "/", vec![4]).await.unwrap();
+        assert_eq!(next_binary(&mut client).await, [4]);
+
+        assert!(matches!(server.join_room(missing, "lobby"), Err(ServerError::UnknownConnection(i)) if i == missing));
+    }
+
+    /// Connects a websocket client and waits for the SDP offer, so the connection is being serviced
+    async fn connect(addr: SocketAddr) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+
+        assert!(matches!(client.next().await, Some(Ok(Message::Text(_)))));
+
+        client
+    }
+
+    async fn next_binary(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> Vec<u8> {
+        // Skip any signalling or pings
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Binary(bytes))) => return bytes.to_vec(),
+                Some(Ok(Message::Text(_) | Message::Ping(_))) => continue,
+                other => panic!("Expected binary message, got {:?}", other),
             }
         }
     }
+
+    async fn expect_close_frame(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> CloseFrame {
+        // Skip any signalling or pings still in flight
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => return frame,
+                Some(Ok(Message::Text(_) | Message::Ping(_))) => continue,
+                other => panic!("Expected close frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn commands_fail_after_shutdown() {
+        let (mut server, _queue) = ServerBuilder::new().bind("127.0.0.1:0").build().unwrap();
+        let mut other = server.clone();
+
+        server.shutdown(Duration::from_secs(1)).unwrap();
+
+        assert!(matches!(other.broadcast(vec![1]), Err(ServerError::ServerStopped)));
+        assert!(matches!(other.drain(), Err(ServerError::ServerStopped)));
+    }
 }
@@ -0,0 +1,180 @@
+//! Bounded queue of messages waiting to be written to a transport
+//! - Filled by the connection actor, emptied by the task writing to the websocket or a data channel
+//! - Applies an overflow policy once full, rather than blocking or panicking
+//! - Control messages (signalling, pings, close frames) skip the limit
+//! - Records how many bytes the transport itself is still buffering, so slow consumers can be spotted
+
+use std::{collections::VecDeque, sync::{atomic::{AtomicUsize, Ordering}, Mutex, MutexGuard}};
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+/// What to do with a message arriving at a full queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Discard the new message
+    DropNewest,
+    /// Discard the oldest message which can be evicted, or the new message if there is none
+    DropOldest,
+    /// Leave the queue alone, the caller decides what to do
+    Reject,
+}
+
+/// Messages which `Overflow::DropOldest` may discard to make room.
+pub trait Evict {
+    fn evictable(&self) -> bool;
+}
+
+impl Evict for Bytes {
+    fn evictable(&self) -> bool { true }
+}
+
+/// The queue was full, so a message was discarded (or rejected) according to the policy.
+#[derive(Debug)]
+pub struct Full;
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+pub struct Outbound<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    limit: usize,
+    // Bytes written but still held by the transport, e.g. a data channel's buffered amount
+    buffered: AtomicUsize,
+}
+
+impl<T: Evict> Outbound<T> {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            state: Mutex::new(State { items: VecDeque::new(), closed: false }),
+            notify: Notify::new(),
+            limit,
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().expect("Lock should not be poisoned")
+    }
+
+    /// Queue a message, applying the policy if the queue already holds `limit` messages.
+    pub fn push(&self, item: T, overflow: Overflow) -> Result<(), Full> {
+        self.push_within(item, self.limit, overflow)
+    }
+
+    /// Same as push, but with a lower limit, e.g. to keep room for reliable messages.
+    pub fn push_within(&self, item: T, limit: usize, overflow: Overflow) -> Result<(), Full> {
+        let mut state = self.lock();
+        if state.items.len() < limit.min(self.limit) {
+            state.items.push_back(item);
+            drop(state);
+            self.notify.notify_one();
+            return Ok(());
+        }
+
+        if overflow == Overflow::DropOldest && let Some(oldest) = state.items.iter().position(Evict::evictable) {
+            state.items.remove(oldest);
+            state.items.push_back(item);
+        }
+        Err(Full)
+    }
+
+    /// Queue a message regardless of the limit.
+    pub fn push_control(&self, item: T) {
+        self.lock().items.push_back(item);
+        self.notify.notify_one();
+    }
+
+    /// Discard every queued message, e.g. before closing a connection which has fallen behind.
+    pub fn clear(&self) {
+        self.lock().items.clear();
+    }
+
+    /// Stop the writing task once it has taken the remaining messages.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next message, or None once closed and empty. Only one task should wait at a time.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.lock();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // A notification sent since the lock was released is kept as a permit
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.limit
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    pub fn set_buffered(&self, bytes: usize) {
+        self.buffered.store(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use super::{Evict, Outbound, Overflow};
+
+    #[derive(Debug, PartialEq)]
+    struct Item(u8, bool);
+
+    impl Evict for Item {
+        fn evictable(&self) -> bool { self.1 }
+    }
+
+    #[tokio::test]
+    async fn applies_overflow_policy() {
+        let queue = Outbound::new(2);
+        assert!(queue.push(Item(0, false), Overflow::Reject).is_ok());
+        assert!(queue.push(Item(1, true), Overflow::Reject).is_ok());
+
+        // Only evictable messages make room
+        assert!(queue.push(Item(2, true), Overflow::DropNewest).is_err());
+        assert!(queue.push(Item(3, true), Overflow::DropOldest).is_err());
+        assert!(queue.push(Item(4, true), Overflow::Reject).is_err());
+        queue.push_control(Item(5, false));
+        assert_eq!(queue.len(), 3);
+
+        queue.close();
+        assert_eq!(queue.pop().await, Some(Item(0, false)));
+        assert_eq!(queue.pop().await, Some(Item(3, true)));
+        assert_eq!(queue.pop().await, Some(Item(5, false)));
+        assert_eq!(queue.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn wakes_writer() {
+        let queue = std::sync::Arc::new(Outbound::<Bytes>::new(4));
+        let writer = tokio::spawn({
+            let queue = std::sync::Arc::clone(&queue);
+            async move { queue.pop().await }
+        });
+
+        tokio::task::yield_now().await;
+        queue.push_within(Bytes::from_static(b"a"), 1, Overflow::DropNewest).unwrap();
+        assert!(queue.push_within(Bytes::from_static(b"b"), 0, Overflow::DropNewest).is_err());
+        assert_eq!(writer.await.unwrap(), Some(Bytes::from_static(b"a")));
+    }
+}
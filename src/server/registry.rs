@@ -0,0 +1,289 @@
+//! Shared view of the connections held by the server actor
+//! - Written to by the actor as connections are created, move through each stage of connecting, and close
+//! - Read by `Server` handles, so they can reject commands without a round trip to the actor
+//! - Tracks which rooms each connection is in, which handles can change directly
+
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, MutexGuard}};
+
+use crate::{error::ServerError, event::{ConnectionState, Identifier, TransportMode}, info::{ConnectionInfo, Rtt}};
+use super::connection::Queues;
+
+struct Entry {
+    // Set once the connection is open
+    info: Option<ConnectionInfo>,
+    state: ConnectionState,
+    rtt: Rtt,
+    rooms: HashSet<Arc<str>>,
+    queues: Arc<Queues>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Identifier, Entry>,
+    // Members of each room, rooms are removed once empty
+    rooms: HashMap<Arc<str>, HashSet<Identifier>>,
+}
+
+/// Can be freely cloned, will point to the same underlying map.
+#[derive(Default, Clone)]
+pub struct Registry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Registry {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Lock should not be poisoned")
+    }
+
+    /// Track a new connection, which is not yet open.
+    pub fn insert(&self, id: Identifier, queues: Arc<Queues>) {
+        self.lock().entries.insert(id, Entry { info: None, state: ConnectionState::Accepted, rtt: Rtt::default(), rooms: HashSet::new(), queues });
+    }
+
+    /// Mark a connection as open, returning whether its state changed. Connections which are closing stay closing.
+    pub fn set_open(&self, id: Identifier, info: ConnectionInfo) -> bool {
+        match self.lock().entries.get_mut(&id) {
+            Some(entry) => {
+                entry.info = Some(info);
+                let changed = entry.state != ConnectionState::Open && entry.state != ConnectionState::Closing;
+                if changed {
+                    entry.state = ConnectionState::Open;
+                }
+                changed
+            },
+            None => false,
+        }
+    }
+
+    /// Move a connection on to another stage, returning whether it changed. Connections stay closing once they are.
+    pub fn set_state(&self, id: Identifier, state: ConnectionState) -> bool {
+        match self.lock().entries.get_mut(&id) {
+            Some(entry) if entry.state != state && entry.state != ConnectionState::Closing => {
+                entry.state = state;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn state(&self, id: Identifier) -> Result<ConnectionState, ServerError> {
+        match self.lock().entries.get(&id) {
+            Some(entry) => Ok(entry.state),
+            None => Err(ServerError::UnknownConnection(id)),
+        }
+    }
+
+    /// Transport mode of an open connection.
+    pub fn transport_mode(&self, id: Identifier) -> Option<TransportMode> {
+        match self.lock().entries.get(&id) {
+            Some(Entry { info: Some(info), .. }) => Some(info.transport_mode),
+            _ => None,
+        }
+    }
+
+    pub fn set_rtt(&self, id: Identifier, rtt: Rtt) {
+        if let Some(entry) = self.lock().entries.get_mut(&id) {
+            entry.rtt = rtt;
+        }
+    }
+
+    pub fn rtt(&self, id: Identifier) -> Result<Rtt, ServerError> {
+        match self.lock().entries.get(&id) {
+            Some(entry) => Ok(entry.rtt),
+            None => Err(ServerError::UnknownConnection(id)),
+        }
+    }
+
+    /// Change the details of an open connection.
+    pub fn update_info(&self, id: Identifier, update: impl FnOnce(&mut ConnectionInfo)) {
+        if let Some(Entry { info: Some(info), .. }) = self.lock().entries.get_mut(&id) {
+            update(info);
+        }
+    }
+
+    /// Stop tracking a connection, which also leaves all of its rooms.
+    pub fn remove(&self, id: Identifier) {
+        let mut inner = self.lock();
+        if let Some(entry) = inner.entries.remove(&id) {
+            for room in entry.rooms {
+                inner.remove_member(&room, id);
+            }
+        }
+    }
+
+    /// Succeeds if the connection is known, whether or not it is open.
+    pub fn check_known(&self, id: Identifier) -> Result<(), ServerError> {
+        match self.lock().entries.contains_key(&id) {
+            true => Ok(()),
+            false => Err(ServerError::UnknownConnection(id)),
+        }
+    }
+
+    /// Succeeds if the connection is known and open.
+    pub fn check_open(&self, id: Identifier) -> Result<(), ServerError> {
+        match self.lock().entries.get(&id) {
+            Some(Entry { info: Some(_), .. }) => Ok(()),
+            Some(Entry { info: None, .. }) => Err(ServerError::NotYetOpen(id)),
+            None => Err(ServerError::UnknownConnection(id)),
+        }
+    }
+
+    /// Outbound queues of the connection, if it is known.
+    pub fn queues(&self, id: Identifier) -> Option<Arc<Queues>> {
+        self.lock().entries.get(&id).map(|entry| Arc::clone(&entry.queues))
+    }
+
+    /// Details of the connection, if it is known and open.
+    pub fn info(&self, id: Identifier) -> Result<ConnectionInfo, ServerError> {
+        match self.lock().entries.get(&id) {
+            Some(Entry { info: Some(info), .. }) => Ok(info.clone()),
+            Some(Entry { info: None, .. }) => Err(ServerError::NotYetOpen(id)),
+            None => Err(ServerError::UnknownConnection(id)),
+        }
+    }
+
+    /// Open connections the filter accepts, called with the lock held.
+    pub fn open_matching(&self, mut filter: impl FnMut(Identifier, &ConnectionInfo) -> bool) -> Vec<Identifier> {
+        self.lock()
+            .entries
+            .iter()
+            .filter_map(|(id, entry)| entry.info.as_ref().map(|info| (*id, info)))
+            .filter(|(id, info)| filter(*id, info))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Add a known connection to a room, creating the room if needed.
+    pub fn join_room(&self, id: Identifier, room: &str) -> Result<(), ServerError> {
+        let mut inner = self.lock();
+        let Inner { entries, rooms } = &mut *inner;
+        let entry = entries.get_mut(&id).ok_or(ServerError::UnknownConnection(id))?;
+
+        // Share one allocation for the room name
+        let room = match rooms.get_key_value(room) {
+            Some((room, _)) => Arc::clone(room),
+            None => Arc::from(room),
+        };
+        entry.rooms.insert(Arc::clone(&room));
+        rooms.entry(room).or_default().insert(id);
+        Ok(())
+    }
+
+    /// Remove a known connection from a room, which it may not be in.
+    pub fn leave_room(&self, id: Identifier, room: &str) -> Result<(), ServerError> {
+        let mut inner = self.lock();
+        let entry = inner.entries.get_mut(&id).ok_or(ServerError::UnknownConnection(id))?;
+        if entry.rooms.remove(room) {
+            inner.remove_member(room, id);
+        }
+        Ok(())
+    }
+
+    /// Connections in the room, in no particular order.
+    pub fn room_members(&self, room: &str) -> Vec<Identifier> {
+        self.lock().rooms.get(room).map(|members| members.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// Rooms the connection is in, in no particular order.
+    pub fn rooms_of(&self, id: Identifier) -> Result<Vec<String>, ServerError> {
+        match self.lock().entries.get(&id) {
+            Some(entry) => Ok(entry.rooms.iter().map(|room| room.to_string()).collect()),
+            None => Err(ServerError::UnknownConnection(id)),
+        }
+    }
+}
+
+impl Inner {
+    fn remove_member(&mut self, room: &str, id: Identifier) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Queues, Registry};
+    use crate::{config::ServerConfig, error::ServerError, event::{ConnectionState, Identifier, TransportMode}, info::ConnectionInfo};
+
+    fn queues() -> Arc<Queues> {
+        Arc::new(Queues::new(&ServerConfig::default()))
+    }
+
+    #[test]
+    fn tracks_lifecycle() {
+        let registry = Registry::default();
+        let id = Identifier::from(0);
+
+        assert!(matches!(registry.check_open(id), Err(ServerError::UnknownConnection(i)) if i == id));
+
+        registry.insert(id, queues());
+        assert!(registry.check_known(id).is_ok());
+        assert!(matches!(registry.check_open(id), Err(ServerError::NotYetOpen(i)) if i == id));
+        assert!(registry.set_state(id, ConnectionState::OfferSent));
+        assert!(!registry.set_state(id, ConnectionState::OfferSent));
+        assert_eq!(registry.state(id).unwrap(), ConnectionState::OfferSent);
+
+        let info = ConnectionInfo {
+            remote_addr: "127.0.0.1:5000".parse().unwrap(),
+            path: String::from("/"),
+            query: None,
+            headers: Vec::new(),
+            transport_mode: TransportMode::WebSocketOnly,
+            candidate_pair: None,
+            user_data: None,
+        };
+        assert!(registry.set_open(id, info.clone()));
+        assert!(registry.check_open(id).is_ok());
+        assert_eq!(registry.state(id).unwrap(), ConnectionState::Open);
+        assert_eq!(registry.info(id).unwrap().path, "/");
+
+        // Connections which aren't open never match
+        registry.insert(Identifier::from(1), queues());
+        assert_eq!(registry.open_matching(|_, _| true), vec![id]);
+        assert!(registry.open_matching(|_, info| info.path == "/play").is_empty());
+
+        registry.update_info(id, |info| info.transport_mode = TransportMode::WebRtc);
+        assert_eq!(registry.info(id).unwrap().transport_mode, TransportMode::WebRtc);
+
+        assert!(registry.set_state(id, ConnectionState::Closing));
+        assert!(!registry.set_state(id, ConnectionState::Connected));
+        assert!(!registry.set_open(id, info));
+        assert_eq!(registry.state(id).unwrap(), ConnectionState::Closing);
+
+        registry.remove(id);
+        assert!(matches!(registry.check_known(id), Err(ServerError::UnknownConnection(i)) if i == id));
+    }
+
+    #[test]
+    fn tracks_rooms() {
+        let registry = Registry::default();
+        let (a, b) = (Identifier::from(0), Identifier::from(1));
+        registry.insert(a, queues());
+        registry.insert(b, queues());
+
+        registry.join_room(a, "lobby").unwrap();
+        registry.join_room(b, "lobby").unwrap();
+        registry.join_room(b, "match").unwrap();
+
+        let mut members = registry.room_members("lobby");
+        members.sort();
+        assert_eq!(members, vec![a, b]);
+        assert_eq!(registry.rooms_of(a).unwrap(), vec![String::from("lobby")]);
+
+        registry.leave_room(a, "lobby").unwrap();
+        assert_eq!(registry.room_members("lobby"), vec![b]);
+
+        // Closing leaves every room, and empty rooms are forgotten
+        registry.remove(b);
+        assert!(registry.room_members("lobby").is_empty());
+        assert!(registry.lock().rooms.is_empty());
+
+        assert!(matches!(registry.join_room(b, "lobby"), Err(ServerError::UnknownConnection(i)) if i == b));
+    }
+}
@@ -1,8 +1,13 @@
 use std::sync::Arc;
 
-use log::info;
+use log::{info, warn};
 use tokio::{net::UdpSocket, sync::{mpsc, oneshot}};
-use webrtc::{api::{setting_engine::SettingEngine, APIBuilder, API}, ice::{udp_mux::{UDPMuxDefault, UDPMuxParams}, udp_network::UDPNetwork}, peer_connection::RTCPeerConnection};
+use webrtc::{api::{setting_engine::SettingEngine, APIBuilder, API}, ice::{udp_mux::{UDPMuxDefault, UDPMuxParams}, udp_network::UDPNetwork, url::{SchemeType, Url}}, ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer}, peer_connection::{configuration::RTCConfiguration, RTCPeerConnection}};
+
+use crate::{config::{NatCandidateType, ServerConfig}, error::ServerError};
+
+/// Requests which can wait for the API actor, shared by every connection so not sized by `connection_capacity`
+const REQUEST_CAPACITY: usize = 256;
 
 /// Request for a new RTCPeerConnection
 struct Request {
@@ -16,41 +21,64 @@ pub struct RtcApiHandle {
 }
 
 impl RtcApiHandle {
-    pub fn new(listen_addr: &str) -> Self {
-        let (sender, mut receiver) = mpsc::channel::<Request>(1024);
+    /// Spawns the API actor, which will accept inbound WebRTC traffic on the provided socket.
+    pub fn new(socket: UdpSocket, config: Arc<ServerConfig>) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Request>(REQUEST_CAPACITY);
 
-        let listen_addr = listen_addr.to_string();
         tokio::spawn(async move {
-            let api = create_api(&listen_addr).await;
+            let api = create_api(socket, &config);
 
             while let Some(request) = receiver.recv().await {
-                let peer_connection = api
-                    .new_peer_connection(Default::default())
-                    .await
-                    .expect("Should have been created.");
-
-                request.respond_to.send(Arc::new(peer_connection)).expect("Should have been sent.");
+                match api.new_peer_connection(rtc_configuration(&config)).await {
+                    // The connection may have gone away whilst waiting
+                    Ok(peer_connection) => { let _ = request.respond_to.send(Arc::new(peer_connection)); },
+                    Err(err) => warn!("Couldn't create peer connection: {}", err),
+                }
             }
         });
 
         Self { sender }
     }
 
-    pub async fn new_peer_connection(&mut self) -> Arc<RTCPeerConnection> {
+    /// None if the peer connection couldn't be created, or the server is stopping.
+    pub async fn new_peer_connection(&mut self) -> Option<Arc<RTCPeerConnection>> {
         let (respond_to, receiver) = oneshot::channel();
 
-        self.sender.try_send(Request { respond_to  }).expect("Actor should be alive");
+        self.sender.send(Request { respond_to }).await.ok()?;
 
-        receiver.await.expect("Actor should have responded")
+        receiver.await.ok()
     }
 }
 
-/// Creates a new API instance from the WebRTC crate
-async fn create_api(listen_addr: &str) -> API {
+/// Creates a new API instance from the WebRTC crate, using a UDP socket to receive inbound packets
+fn create_api(socket: UdpSocket, config: &ServerConfig) -> API {
     let mut s = SettingEngine::default();
 
-    // Create a UDP socket to receive inbound packets
-    let socket = UdpSocket::bind(listen_addr).await.expect("Opening UDP socket should have succeeded");
+    // Detect peers whose network has silently died
+    s.set_ice_timeouts(
+        Some(config.ice_disconnected_timeout),
+        Some(config.ice_failed_timeout),
+        Some(config.ice_keepalive_interval),
+    );
+
+    // Advertise public IPs when behind NAT
+    if !config.nat_1to1_ips.is_empty() {
+        let candidate_type = match config.nat_1to1_candidate_type {
+            NatCandidateType::Host => RTCIceCandidateType::Host,
+            NatCandidateType::ServerReflexive => RTCIceCandidateType::Srflx,
+        };
+        s.set_nat_1to1_ips(config.nat_1to1_ips.iter().map(|ip| ip.to_string()).collect(), candidate_type);
+    }
+
+    // Restrict where candidates are gathered
+    if let Some(filter) = &config.interface_filter {
+        let filter = Arc::clone(&filter.0);
+        s.set_interface_filter(Box::new(move |name| filter(name)));
+    }
+    if let Some(filter) = &config.ip_filter {
+        let filter = Arc::clone(&filter.0);
+        s.set_ip_filter(Box::new(move |ip| filter(ip)));
+    }
 
     s.set_udp_network(UDPNetwork::Muxed(UDPMuxDefault::new(
         UDPMuxParams::new(socket)
@@ -65,12 +93,61 @@ async fn create_api(listen_addr: &str) -> API {
     api
 }
 
+/// Configuration for each new peer connection.
+fn rtc_configuration(config: &ServerConfig) -> RTCConfiguration {
+    let ice_servers = config.ice_servers
+        .iter()
+        .map(|server| RTCIceServer {
+            urls: server.urls.clone(),
+            username: server.username.clone(),
+            credential: server.credential.clone(),
+            ..Default::default()
+        })
+        .collect();
+
+    RTCConfiguration { ice_servers, ..Default::default() }
+}
+
+/// Checks the ICE server URLs up front, as peer connections can't be created with invalid ones.
+pub fn validate_ice_servers(config: &ServerConfig) -> Result<(), ServerError> {
+    for server in &config.ice_servers {
+        for raw in &server.urls {
+            let url = Url::parse_url(raw).map_err(|err| ServerError::InvalidConfig(format!("ICE server URL '{}': {}", raw, err)))?;
+
+            let is_turn = matches!(url.scheme, SchemeType::Turn | SchemeType::Turns);
+            if is_turn && (server.username.is_empty() || server.credential.is_empty()) {
+                return Err(ServerError::InvalidConfig(format!("TURN server '{}' needs a username and credential", raw)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::server::webrtc::api::create_api;
+    use tokio::net::UdpSocket;
+
+    use crate::{config::{IceServer, ServerConfig}, error::ServerError, server::webrtc::api::{create_api, validate_ice_servers}};
 
     #[tokio::test]
     async fn api_builds() {
-        let _ = create_api("0.0.0.0:3001").await;
+        let socket = UdpSocket::bind("0.0.0.0:3001").await.unwrap();
+        let _ = create_api(socket, &ServerConfig::default());
+    }
+
+    #[test]
+    fn ice_servers_are_validated() {
+        let mut config = ServerConfig {
+            ice_servers: vec![IceServer::stun("stun:stun.l.google.com:19302"), IceServer::turn("turn:turn.example.com:3478", "user", "pass")],
+            ..Default::default()
+        };
+        assert!(validate_ice_servers(&config).is_ok());
+
+        config.ice_servers = vec![IceServer::stun("turn:turn.example.com:3478")];
+        assert!(matches!(validate_ice_servers(&config), Err(ServerError::InvalidConfig(_))));
+
+        config.ice_servers = vec![IceServer::stun("not a url")];
+        assert!(matches!(validate_ice_servers(&config), Err(ServerError::InvalidConfig(_))));
     }
 }
\ No newline at end of file
@@ -1,27 +1,45 @@
 use log::info;
-use std::sync::Arc;
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Weak};
 use tokio::sync::mpsc;
-use webrtc::{data_channel::RTCDataChannel, peer_connection::RTCPeerConnection};
+use webrtc::{data_channel::RTCDataChannel, ice_transport::ice_connection_state::RTCIceConnectionState, peer_connection::{peer_connection_state::RTCPeerConnectionState, signaling_state::RTCSignalingState, RTCPeerConnection}};
 
-use super::{signal, RTCEvent};
+use crate::event::{ConnectionState, Message, Transport};
+use super::{signal, stats, RTCEvent};
 
 /// Configures the event handlers of an RTCDataChannel to log and send appropriate signals down the provided 'emit' channel.
-pub fn configure_data_channel(data_channel: &Arc<RTCDataChannel>, emit: mpsc::Sender<RTCEvent>) {
-    // Notify parent actor that connection is open
+/// 
+/// 'unopened' is shared between every data channel of the peer connection, the last to open notifies the parent actor.
+pub fn configure_data_channel(data_channel: &Arc<RTCDataChannel>, peer_connection: &Arc<RTCPeerConnection>, unopened: Arc<AtomicUsize>, emit: mpsc::Sender<RTCEvent>) {
+    let label: Arc<str> = Arc::from(data_channel.label());
+
+    // Notify parent actor once every data channel is open, along with the selected candidate pair
     {
         let emit = emit.clone();
+        let label = Arc::clone(&label);
+        // Weak, as the peer connection owns this handler
+        let peer_connection = Arc::downgrade(peer_connection);
         data_channel.on_open(Box::new(move || {
-            info!("Data channel open");
-            emit.try_send(RTCEvent::Opened).expect("Parent actor should be alive.");
-            Box::pin(async {})
+            info!("Data channel '{}' open", label);
+            Box::pin(async move {
+                if unopened.fetch_sub(1, Ordering::AcqRel) != 1 {
+                    return;
+                }
+
+                let candidate_pair = match Weak::upgrade(&peer_connection) {
+                    Some(peer_connection) => stats::selected_candidate_pair(&peer_connection).await,
+                    None => None,
+                };
+                let _ = emit.send(RTCEvent::Opened(candidate_pair)).await;
+            })
         }));
     }
     
     // Notify parent actor that connection is closed
     {
         let emit = emit.clone();
+        let label = Arc::clone(&label);
         data_channel.on_close(Box::new(move || {
-            info!("Data channel close");
+            info!("Data channel '{}' close", label);
             let _ = emit.try_send(RTCEvent::Closed);
             Box::pin(async {})
         }));
@@ -31,22 +49,66 @@ pub fn configure_data_channel(data_channel: &Arc<RTCDataChannel>, emit: mpsc::Se
     {
         let emit = emit.clone();
         data_channel.on_message(Box::new(move |msg| {
-            info!("Data channel message");
-            emit.try_send(RTCEvent::ApplicationMessageReceived(msg.data.to_vec())).expect("Parent actor should be alive.");
-            Box::pin(async {})
+            info!("Data channel '{}' message", label);
+            let message = Message::new(Transport::DataChannel(Arc::clone(&label)), msg.data);
+            let emit = emit.clone();
+            // Waiting for room holds up reading from the data channel, rather than dropping the message
+            Box::pin(async move {
+                let _ = emit.send(RTCEvent::ApplicationMessageReceived(message)).await;
+            })
         }));
     }
 }
 
 /// Configures the event handlers of an RTCPeerConnection to log and send appropriate signals down the provided 'emit' channel.
 pub fn configure_peer_connection(peer_connection: &RTCPeerConnection, emit: mpsc::Sender<RTCEvent>) {
+    // Notify parent actor once the client's answer is applied, which returns signalling to stable after the offer
+    {
+        let emit = emit.clone();
+        peer_connection.on_signaling_state_change(Box::new(move |state| {
+            info!("Signalling state: {}", state);
+            if state == RTCSignalingState::Stable {
+                let _ = emit.try_send(RTCEvent::StateChanged(ConnectionState::AnswerApplied));
+            }
+            Box::pin(async {})
+        }));
+    }
+
+    // Notify parent actor when ICE starts checking, or if it fails
+    {
+        let emit = emit.clone();
+        peer_connection.on_ice_connection_state_change(Box::new(move |state| {
+            info!("ICE connection state: {}", state);
+            match state {
+                RTCIceConnectionState::Checking => { let _ = emit.try_send(RTCEvent::StateChanged(ConnectionState::IceChecking)); },
+                RTCIceConnectionState::Failed => { let _ = emit.try_send(RTCEvent::Failed); },
+                _ => {},
+            }
+            Box::pin(async {})
+        }));
+    }
+
+    // Notify parent actor when the peer connection connects, or if it fails
+    {
+        let emit = emit.clone();
+        peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+            info!("Peer connection state: {}", state);
+            match state {
+                RTCPeerConnectionState::Connected => { let _ = emit.try_send(RTCEvent::StateChanged(ConnectionState::Connected)); },
+                RTCPeerConnectionState::Failed => { let _ = emit.try_send(RTCEvent::Failed); },
+                _ => {},
+            }
+            Box::pin(async {})
+        }));
+    }
+
     // Handle ICE candidate challenges by sending them by another channel (actor emits them)
     peer_connection.on_ice_candidate(Box::new(move |candidate| {
-        if let Some(candidate) = candidate {
-            emit.try_send(
-                RTCEvent::EmitSignallingMessage(signal::generate_ice_candidate_message(candidate))
-            ).expect("Parent actor should be alive.");
-        }
-        Box::pin(async {})
+        let emit = emit.clone();
+        Box::pin(async move {
+            if let Some(candidate) = candidate {
+                let _ = emit.send(RTCEvent::EmitSignallingMessage(signal::generate_ice_candidate_message(candidate))).await;
+            }
+        })
     }));
 }
\ No newline at end of file
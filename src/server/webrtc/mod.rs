@@ -1,22 +1,35 @@
-pub use api::RtcApiHandle;
+pub use api::{validate_ice_servers, RtcApiHandle};
+use bytes::Bytes;
+use log::warn;
 use signal::handle_signalling_message;
-use std::sync::Arc;
+use std::sync::{atomic::AtomicUsize, Arc};
 
-use tokio::sync::mpsc;
-use webrtc::{data_channel::RTCDataChannel, peer_connection::RTCPeerConnection};
+use tokio::{sync::mpsc, task::JoinHandle};
+use crate::{config::{DataChannelConfig, Reliability, ServerConfig}, event::{ConnectionState, Message}, info::CandidatePair};
+use super::outbound::Outbound;
+use webrtc::{data_channel::{data_channel_init::RTCDataChannelInit, RTCDataChannel}, peer_connection::RTCPeerConnection};
 
 
 #[derive(Debug)]
 pub enum RTCEvent {
-    Opened,
+    /// Every data channel is open, along with the candidate pair ICE selected
+    Opened(Option<CandidatePair>),
     Closed,
-    ApplicationMessageReceived(Vec<u8>),
-    EmitSignallingMessage(String)
+    /// ICE or the peer connection as a whole has failed, so the data channel won't recover
+    Failed,
+    ApplicationMessageReceived(Message),
+    EmitSignallingMessage(String),
+    /// The peer connection has reached another stage of connecting
+    StateChanged(ConnectionState),
 }
 
 enum RTCHandleMessage {
-    Send(Vec<u8>),
-    ReceiveSignalling(String)
+    ReceiveSignalling(String),
+    /// Text to send on the first data channel, to measure its round-trip time
+    Ping(String),
+    SampleBuffered,
+    /// Finish sending what is queued on the data channels, then close the peer connection
+    Close,
 }
 
 /// Handles serialization of ICE/SDP messages
@@ -25,67 +38,121 @@ mod signal;
 mod handlers;
 /// Handles the WebRTC API, which initialises new data channels over UDP
 mod api;
+/// Reads connection details out of the peer connection's stats
+mod stats;
 
 pub struct RTCHandle {
-    sender: mpsc::Sender<RTCHandleMessage>
+    sender: mpsc::Sender<RTCHandleMessage>,
+    task: Option<JoinHandle<()>>,
 }
 
 impl RTCHandle {
-    pub fn new(emit: mpsc::Sender<RTCEvent>, mut api: RtcApiHandle) -> Self {
-        let (sender, mut receiver) = mpsc::channel(1024);
-
-        tokio::spawn(async move {
-            // Create a new RTCPeerConnection
-            let peer_connection = api.new_peer_connection().await;
+    /// Messages to send on each data channel are taken from the queue at the same index, in the order they were configured.
+    pub fn new(emit: mpsc::Sender<RTCEvent>, mut api: RtcApiHandle, config: Arc<ServerConfig>, queues: Vec<Arc<Outbound<Bytes>>>) -> Self {
+        let (sender, mut receiver) = mpsc::channel(config.connection_capacity);
+
+        let task = tokio::spawn(async move {
+            // Create a new RTCPeerConnection, the connection carries on without WebRTC if it can't be
+            let Some(peer_connection) = api.new_peer_connection().await else {
+                let _ = emit.send(RTCEvent::Failed).await;
+                return;
+            };
 
-            // Create a data channel (only on the initiator side)
-            let data_channel = peer_connection.create_data_channel("game", None).await.expect("Should have been created.");
+            // Create the data channels (only on the initiator side), counting down as they open
+            let unopened = Arc::new(AtomicUsize::new(config.data_channels.len()));
+            let mut channels = Vec::new();
+            let mut send_tasks = Vec::new();
+            for (channel, queue) in config.data_channels.iter().zip(queues) {
+                let data_channel = match peer_connection.create_data_channel(&channel.label, Some(data_channel_init(channel))).await {
+                    Ok(data_channel) => data_channel,
+                    Err(err) => {
+                        warn!("Couldn't create data channel '{}': {}", channel.label, err);
+                        let _ = emit.send(RTCEvent::Failed).await;
+                        let _ = peer_connection.close().await;
+                        return;
+                    },
+                };
+
+                handlers::configure_data_channel(&data_channel, &peer_connection, Arc::clone(&unopened), emit.clone());
+                channels.push((Arc::clone(&data_channel), Arc::clone(&queue)));
+
+                // Task to send messages via the data channel
+                send_tasks.push(start_send_task(data_channel, queue));
+            }
 
-            // Setup handlers 
-            handlers::configure_data_channel(&data_channel, emit.clone());
+            // Setup handlers
             handlers::configure_peer_connection(&peer_connection, emit.clone());
-                        
+
             // Create and send SDP offer
-            emit.send(RTCEvent::EmitSignallingMessage(signal::generate_sdp_offer_message(&peer_connection).await)).await.expect("Parent actor should be alive");
+            if emit.send(RTCEvent::EmitSignallingMessage(signal::generate_sdp_offer_message(&peer_connection).await)).await.is_err() {
+                warn!("Connection closed before the SDP offer was sent");
+            }
+            let _ = emit.send(RTCEvent::StateChanged(ConnectionState::OfferSent)).await;
 
-            // Task to send messages via the data channel 
-            let sender_data_channel = start_send_task(data_channel);
-            
-            // Create actor 
+            // Create actor
             let mut actor = Actor {
-                sender_data_channel,
-                peer_connection
+                peer_connection,
+                channels,
             };
 
             // Event loop
-            while let Some(message) = receiver.recv().await {
-                actor.handle_message(message);
+            loop {
+                match receiver.recv().await {
+                    Some(RTCHandleMessage::Close) | None => break,
+                    Some(message) => actor.handle_message(message),
+                }
+            }
+
+            // Asked to close or the handle has been dropped, either way the queues are closed so the send tasks finish once they are empty
+            for task in send_tasks {
+                let _ = task.await;
+            }
+
+            // Then tear down the peer connection (which closes the data channels)
+            if let Err(err) = actor.peer_connection.close().await {
+                warn!("Error closing peer connection: {}", err);
             }
         });
 
-        Self { sender }
+        Self { sender, task: Some(task) }
     }
 
-    pub fn send_message(&mut self, message: Vec<u8>) {
-        self.sender.try_send(RTCHandleMessage::Send(message)).expect("Actor should be alive");
+    pub fn receive_signalling_message(&mut self, message: String) {
+        if let Err(err) = self.sender.try_send(RTCHandleMessage::ReceiveSignalling(message)) {
+            warn!("Couldn't queue signalling message for peer connection: {}", err);
+        }
     }
 
-    pub fn receive_signalling_message(&mut self, message: String) {
-        self.sender.try_send(RTCHandleMessage::ReceiveSignalling(message)).expect("Actor should be alive");
+    /// Waits for the data channels to send what is queued on them and the peer connection to close, the queues should be closed first.
+    pub async fn close(&mut self) {
+        let _ = self.sender.send(RTCHandleMessage::Close).await;
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    /// Refresh how much each data channel is buffering, which otherwise only happens when sending on it.
+    pub fn sample_buffered(&mut self) {
+        // Sampled periodically, so it doesn't matter if one is skipped
+        let _ = self.sender.try_send(RTCHandleMessage::SampleBuffered);
+    }
+
+    pub fn ping(&mut self, text: String) {
+        if let Err(err) = self.sender.try_send(RTCHandleMessage::Ping(text)) {
+            warn!("Couldn't queue ping for peer connection: {}", err);
+        }
     }
 }
 
 struct Actor {
-    sender_data_channel: mpsc::Sender<Vec<u8>>,
     peer_connection: Arc<RTCPeerConnection>,
+    // Each data channel with its queue, in the order they were configured
+    channels: Vec<(Arc<RTCDataChannel>, Arc<Outbound<Bytes>>)>,
 }
 
 impl Actor {
     pub fn handle_message(&mut self, message: RTCHandleMessage) {
         match message {
-            RTCHandleMessage::Send(bytes) => {
-                self.sender_data_channel.try_send(bytes).expect("Send task should be alive");
-            },
             RTCHandleMessage::ReceiveSignalling(message) => {
                 let peer_connection = Arc::clone(&self.peer_connection);
 
@@ -93,21 +160,73 @@ impl Actor {
                     handle_signalling_message(peer_connection, message).await;
                 });
             },
+            RTCHandleMessage::Ping(text) => {
+                let Some((data_channel, _)) = self.channels.first().cloned() else { return };
+
+                tokio::spawn(async move {
+                    if let Err(err) = data_channel.send_text(text).await {
+                        warn!("Error sending ping on data channel '{}': {}", data_channel.label(), err);
+                    }
+                });
+            },
+            RTCHandleMessage::SampleBuffered => {
+                let channels = self.channels.clone();
+
+                tokio::spawn(async move {
+                    for (data_channel, queue) in channels {
+                        queue.set_buffered(data_channel.buffered_amount().await);
+                    }
+                });
+            },
+            RTCHandleMessage::Close => {}, // Handled by the event loop
         }
     }
 }
 
-/// Spawns a task whose job is to send messages through the provided datachannel, which is only possible in an async context.
-/// 
-/// Task finishes when returns when all senders are dropped.
-fn start_send_task(data_channel: Arc<RTCDataChannel>) -> mpsc::Sender<Vec<u8>> {
-    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(1024);
+/// Converts to the options understood by the WebRTC crate.
+fn data_channel_init(config: &DataChannelConfig) -> RTCDataChannelInit {
+    let (max_retransmits, max_packet_life_time) = match config.reliability {
+        Reliability::Reliable => (None, None),
+        Reliability::MaxRetransmits(retransmits) => (Some(retransmits), None),
+        Reliability::MaxPacketLifeTime(lifetime) => (None, Some(lifetime.as_millis().min(u16::MAX as u128) as u16)),
+    };
+
+    RTCDataChannelInit {
+        ordered: Some(config.ordered),
+        max_retransmits,
+        max_packet_life_time,
+        ..Default::default()
+    }
+}
 
+/// Spawns a task whose job is to send messages from the queue through the provided datachannel, which is only possible in an async context.
+/// 
+/// Task finishes once the queue is closed and empty.
+fn start_send_task(data_channel: Arc<RTCDataChannel>, queue: Arc<Outbound<Bytes>>) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Some(message) = receiver.recv().await {
-            data_channel.send(&bytes::Bytes::copy_from_slice(&message)).await.expect("Should have sent");
+        while let Some(message) = queue.pop().await {
+            if let Err(err) = data_channel.send(&message).await {
+                warn!("Error sending on data channel '{}': {}", data_channel.label(), err);
+            }
+            // Tracked so that a client which can't keep up is noticed
+            queue.set_buffered(data_channel.buffered_amount().await);
         }
-    });
+    })
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-    sender
-}
\ No newline at end of file
+    use crate::config::{DataChannelConfig, Reliability};
+    use super::data_channel_init;
+
+    #[test]
+    fn converts_data_channel_config() {
+        let init = data_channel_init(&DataChannelConfig::default());
+        assert_eq!((init.ordered, init.max_retransmits, init.max_packet_life_time), (Some(false), Some(0), None));
+
+        let config = DataChannelConfig { reliability: Reliability::MaxPacketLifeTime(Duration::from_secs(100)), ..Default::default() };
+        let init = data_channel_init(&config);
+        assert_eq!((init.max_retransmits, init.max_packet_life_time), (None, Some(u16::MAX)));
+    }
+}
@@ -0,0 +1,27 @@
+use webrtc::{peer_connection::RTCPeerConnection, stats::StatsReportType};
+
+use crate::info::{Candidate, CandidatePair};
+
+/// Looks up the candidate pair ICE has selected, which is the one marked as nominated in the stats report.
+pub async fn selected_candidate_pair(peer_connection: &RTCPeerConnection) -> Option<CandidatePair> {
+    let reports = peer_connection.get_stats().await.reports;
+
+    let pair = reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+        _ => None,
+    })?;
+
+    let candidate = |id: &str| match reports.get(id) {
+        Some(StatsReportType::LocalCandidate(stats) | StatsReportType::RemoteCandidate(stats)) => Some(Candidate {
+            ip: stats.ip.clone(),
+            port: stats.port,
+            kind: stats.candidate_type.to_string(),
+        }),
+        _ => None,
+    };
+
+    Some(CandidatePair {
+        local: candidate(&pair.local_candidate_id)?,
+        remote: candidate(&pair.remote_candidate_id)?,
+    })
+}
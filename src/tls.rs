@@ -0,0 +1,104 @@
+//! TLS for the websocket listener, enabled with the `tls` feature
+//! - Load a certificate chain and private key from PEM files, or use an existing rustls configuration
+//! - Swap in new certificates whilst the server is running, which only affects new connections
+
+use std::{fmt::Debug, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+use tokio_rustls::{rustls::{self, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}}, TlsAcceptor};
+
+use crate::error::ServerError;
+
+/// Certificates used to serve `wss://`, set with `ServerBuilder::tls`.
+///
+/// Can be freely cloned, will point to the same certificates. Keep a clone to reload them later, e.g. when they are renewed.
+#[derive(Clone)]
+pub struct TlsConfig {
+    current: Arc<RwLock<Arc<rustls::ServerConfig>>>,
+    // Set when loaded from PEM files, so they can be read again
+    files: Option<Arc<(PathBuf, PathBuf)>>,
+}
+
+impl TlsConfig {
+    /// Load a certificate chain and private key from PEM files.
+    pub fn from_pem_files(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        let files = (cert_path.as_ref().to_path_buf(), key_path.as_ref().to_path_buf());
+        let config = load_pem_files(&files.0, &files.1)?;
+
+        Ok(Self {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+            files: Some(Arc::new(files)),
+        })
+    }
+
+    /// Use an existing rustls configuration.
+    pub fn from_rustls(config: Arc<rustls::ServerConfig>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(config)),
+            files: None,
+        }
+    }
+
+    /// Read the PEM files again, keeping the current certificates if they can't be loaded.
+    ///
+    /// Does nothing if created with `from_rustls`, use `replace` instead.
+    pub fn reload(&self) -> Result<(), ServerError> {
+        if let Some(files) = &self.files {
+            let config = load_pem_files(&files.0, &files.1)?;
+            self.replace(Arc::new(config));
+        }
+        Ok(())
+    }
+
+    /// Use a different rustls configuration for new connections.
+    pub fn replace(&self, config: Arc<rustls::ServerConfig>) {
+        *self.current.write().expect("Lock should not be poisoned") = config;
+    }
+
+    /// Acceptor using the current certificates.
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::clone(&self.current.read().expect("Lock should not be poisoned")))
+    }
+}
+
+impl Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.files {
+            Some(files) => write!(f, "TlsConfig({}, {})", files.0.display(), files.1.display()),
+            None => write!(f, "TlsConfig(rustls)"),
+        }
+    }
+}
+
+fn load_pem_files(cert_path: &Path, key_path: &Path) -> Result<rustls::ServerConfig, ServerError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| ServerError::Tls(format!("Couldn't read certificates from {}: {}", cert_path.display(), err)))?;
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| ServerError::Tls(format!("Couldn't read private key from {}: {}", key_path.display(), err)))?;
+
+    // Pick the provider explicitly, in case another crate enables a different one
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| ServerError::Tls(err.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| ServerError::Tls(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsConfig;
+    use crate::error::ServerError;
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/key.pem");
+
+    #[test]
+    fn loads_and_reloads_pem_files() {
+        let tls = TlsConfig::from_pem_files(CERT, KEY).unwrap();
+        assert!(tls.reload().is_ok());
+
+        assert!(matches!(TlsConfig::from_pem_files("missing.pem", KEY), Err(ServerError::Tls(_))));
+        assert!(matches!(TlsConfig::from_pem_files(CERT, CERT), Err(ServerError::Tls(_))));
+    }
+}